@@ -1,39 +1,116 @@
 ---@meta
----@alias ToolChain "Msvc" | "Gcc" | "Clang" | "Zig" | { compiler: string, linker: string }
+---@alias ToolChain "Msvc" | "Gcc" | "Clang" | "Zig" | CustomToolChain
+---@class CustomToolChain
+---@field compiler string
+---@field linker string
+---@field obj_file_ext ?string
+---@field input_flag ?string
+---@field output_flag ?string
+---@field include_flag ?string
+---@field define_flag ?string
+---@field archiver ?string
+---@field linker_output_flag ?string
+---@field link_lib_flag ?string
+---@field link_dir_flag ?string
+---@field sysroot ?string
+
 ---@alias BinaryType "Executable" | "DynLib" | "StaticLib"
----@alias ErrorFlag "Error" | "Pedantic" | "Extra" | "All" | "DeprecatedDeclarations"
+---@alias ErrorFlag "Error" | { ErrorOn: string } | "Pedantic" | "Extra" | "All" | "DeprecatedDeclarations"
 ---@alias OptimizationLevel "Debug" | "Release" | "O0" | "O1" | "O2" | "O3" | "OSize"
 ---@alias Os "Windows" | "Linux" | "MacOs" | "UnixLike"
+---@alias Target "WindowX86" | "WindowsX64" | "LinuxX86" | "LinuxX64"
 
 ---@class Args
 ---@field warnings ?ErrorFlag[]
 ---@field no_warnings ?ErrorFlag[]
----@field custom ?string[]
+---@field custom ?string[] deprecated: applies to compiles and links alike
+---@field compile_flags ?string[]
+---@field link_flags ?string[]
+---@field defines ?string[]
+
+---@class PlatformFiles
+---@field linux ?string[]
+---@field windows ?string[]
+---@field macos ?string[]
+
+---Files matching `pattern`, relative to the build script's directory (the working
+---directory with `--relative-to-cwd`); supports `**` and `{a,b}`.
+---@param pattern string
+---@return string[]
+function glob(pattern) end
+
+---@class fs
+---@field exists async fun(path: string): boolean
+---@field read_to_string async fun(path: string): string
+---@field mkdir async fun(path: string)
+---@field list_dir async fun(path: string): string[]
+fs = {}
 
 ---@class JoinHandle
 
 ---@class Binary
 ---@field build async fun(self: Binary): JoinHandle
 ---@field build_and_install async fun(self: Binary): string?
+---@field sources async fun(self: Binary): string[]
 
 ---@class Graph
+---@field name ?string
 ---@field tool_chain ToolChain
 ---@field opt_level OptimizationLevel
 ---@field type ?BinaryType
----@field files string[]
+---@field files string[] `@list.txt` entries add the sources listed in that file
+---@field platform_files ?PlatformFiles added to `files` when building for that OS
+---@field objects ?string[]
 ---@field output ?string
+---@field output_suffix_by_profile ?boolean
+---@field no_lib_prefix ?boolean
+---@field bin_dir ?boolean | string
+---@field pre_build ?string | string[]
+---@field post_build ?string | string[]
 ---@field src_dir ?string
 ---@field includes ?string[]
+---@field system_includes ?string[] searched after `includes` and before the sysroot
 ---@field lib_paths ?string[]
 ---@field libs ?string[]
+---@field whole_archive_libs ?string[]
+---@field linker_args ?string[] passed to the linker verbatim, after the objects
+---@field linker_args_position ?("BeforeLibs" | "AfterLibs")
+---@field frameworks ?string[]
+---@field framework_paths ?string[]
+---@field rpaths ?string[]
+---@field pkg_config ?string[]
 ---@field args ?Args
 ---@field excludes ?string[]
+---@field std ?string
+---@field language ?("C" | "Cpp")
+---@field target ?Target
+---@field zig_target ?string
+---@field sysroot ?string
+---@field env ?table<string, string>
+---@field unity_batch_size ?integer
+---@field compiler_launcher ?string
+---@field cache_dir ?string
+---@field install_prefix ?string
+---@field install_headers ?string[]
+---@field debug_info ?integer
+---@field map_file ?boolean | string
+---@field pic ?boolean
+---@field pie ?boolean
+---@field lto ?("Off" | "Thin" | "Full")
+---@field strip ?boolean
+---@field gc_sections ?boolean
+---@field sanitizers ?("address" | "undefined" | "thread" | "leak" | "memory")[]
+---@field overrides ?table<string, Args>
 
 ---@class Build
----@field add_binary fun(self: Build, binary: Graph): Binary
+---@field add_binary fun(self: Build, binary: Graph, deps: Binary[]?): Binary
 ---@field install async fun(self: Build, join_handle: JoinHandle): string?
+---@field join async fun(self: Build, join_handles: JoinHandle[]): (string?)[] waits for all handles at once
 ---@field default_toolchain fun(self: Build): ToolChain
+---@field toolchain_version async fun(self: Build, tool_chain: ToolChain?): string?
 ---@field default_opt_level fun(self: Build): OptimizationLevel
 ---@field wants_run fun(self: Build): boolean
 ---@field run async fun(self: Build, binary: string, args: string[]?): boolean
 ---@field host_os fun(self: Build): Os
+---@field should_generate_database fun(self: Build): boolean
+---@field generate_database async fun(self: Build, path: string?): boolean
@@ -0,0 +1,78 @@
+use std::path::PathBuf;
+
+/// Why compiling or linking a graph failed, for callers that react to the cause
+/// rather than only report it.
+#[derive(Debug, thiserror::Error)]
+pub enum BuildError {
+    #[error("failed to compile `{}`; compilation aborted", file.display())]
+    CompileFailed { file: PathBuf, stderr: String },
+    #[error("failed to link `{}`; compilation aborted", output.display())]
+    LinkFailed { output: PathBuf },
+    #[error("`{tool}` not found on PATH; install it or set a custom toolchain")]
+    ToolchainNotFound { tool: String },
+    #[error("source file not found: {}", path.display())]
+    SourceMissing { path: PathBuf },
+    /// `what` names the step, e.g. ``compilation of `main.c` ``.
+    #[error("{what} timed out after {secs} s")]
+    Timeout { what: String, secs: u64 },
+    /// Every failure of a graph that kept going after its first one.
+    #[error("{}", join(.0))]
+    Multiple(Vec<BuildError>),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for BuildError {
+    /// Recovers a `BuildError` that passed through an `anyhow::Result` on its way up.
+    fn from(error: anyhow::Error) -> Self {
+        error
+            .downcast::<BuildError>()
+            .unwrap_or_else(BuildError::Other)
+    }
+}
+
+fn join(errors: &[BuildError]) -> String {
+    errors
+        .iter()
+        .map(|error| format!("{error:#}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_errors_survive_a_trip_through_anyhow() {
+        let error = anyhow::Error::from(BuildError::SourceMissing {
+            path: PathBuf::from("main.c"),
+        });
+        assert!(matches!(
+            BuildError::from(error),
+            BuildError::SourceMissing { path } if path.as_os_str() == "main.c"
+        ));
+        let other = BuildError::from(anyhow::anyhow!("disk full"));
+        assert!(matches!(&other, BuildError::Other(_)));
+        assert_eq!(other.to_string(), "disk full");
+    }
+
+    #[test]
+    fn multiple_errors_are_shown_one_per_line() {
+        let error = BuildError::Multiple(vec![
+            BuildError::CompileFailed {
+                file: PathBuf::from("a.c"),
+                stderr: String::new(),
+            },
+            BuildError::LinkFailed {
+                output: PathBuf::from("app"),
+            },
+        ]);
+        assert_eq!(
+            error.to_string(),
+            "failed to compile `a.c`; compilation aborted\nfailed to link `app`; compilation aborted"
+        );
+    }
+}
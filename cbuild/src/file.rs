@@ -1,142 +1,1465 @@
-use crate::CommandExt;
+use crate::{CommandExt, response_file};
 
-use super::graph::{CompilerFlags, ToolChain};
-use anyhow::{Context, Result};
-use std::path::PathBuf;
+use super::error::BuildError;
+use super::graph::{CompilerFlags, Language, Lto, OptimizationLevel, ToolChain};
+use super::progress::Progress;
+use super::reporter::{Message, Reporter};
+use super::timings::{Step, Timings};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
 use tokio::process::Command;
+use tokio::sync::OnceCell;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct OutputFile {
     pub path: PathBuf,
+    pub language: Language,
+}
+
+/// Whether an object or binary has to be rebuilt, and why.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    UpToDate,
+    Rebuild(Reason),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reason {
+    FullRebuild,
+    /// The output itself, or one of the files recorded next to it, is gone.
+    Missing(PathBuf),
+    /// Nothing records how the output was last built.
+    NoRecord,
+    FlagsChanged,
+    /// An input was modified after the output was written.
+    Newer(PathBuf),
+    /// An input recorded in the depfile no longer exists.
+    Removed(PathBuf),
+    /// The content hash differs, yet no input is newer than the output.
+    ContentsChanged,
+}
+
+impl std::fmt::Display for Reason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FullRebuild => write!(f, "full rebuild requested"),
+            Self::Missing(path) => write!(f, "`{}` does not exist", path.display()),
+            Self::NoRecord => write!(f, "no record of a previous build"),
+            Self::FlagsChanged => write!(f, "flags changed"),
+            Self::Newer(path) => write!(f, "`{}` is newer than the output", path.display()),
+            Self::Removed(path) => write!(f, "`{}` was removed", path.display()),
+            Self::ContentsChanged => write!(f, "contents changed"),
+        }
+    }
+}
+
+/// Objects compiled during one build, keyed by their compile command without the output path,
+/// so binaries sharing a source with identical flags and toolchain compile it only once.
+#[derive(Debug, Default)]
+pub struct ObjectCache {
+    objects: Mutex<HashMap<String, Arc<OnceCell<OutputFile>>>>,
+}
+
+impl ObjectCache {
+    /// Compiles `file` unless an identical compile already ran, in which case its object is reused.
+    pub async fn compile(
+        &self,
+        file: &InputFile,
+        progress: &Progress,
+    ) -> Result<OutputFile, BuildError> {
+        let cell = self
+            .objects
+            .lock()
+            .unwrap()
+            .entry(file.compile_key())
+            .or_default()
+            .clone();
+        let mut compiled = false;
+        let output = cell
+            .get_or_try_init(|| {
+                compiled = true;
+                file.compile(progress)
+            })
+            .await?;
+        if !compiled {
+            progress.finish(&file.path, false);
+        }
+        Ok(output.clone())
+    }
+}
+
+/// A single entry of a `compile_commands.json` database.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompileCommand {
+    pub directory: PathBuf,
+    /// The compiler and its arguments unsplit, so paths with spaces survive.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub arguments: Vec<String>,
+    /// Entries written by other tools may hold a shell command line instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    pub file: PathBuf,
+}
+
+/// Compile settings shared by every source file of a graph.
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    pub tool_chain: ToolChain,
+    pub opt_level: OptimizationLevel,
+    pub args: CompilerFlags,
+    pub includes: Vec<PathBuf>,
+    pub system_includes: Vec<PathBuf>,
+    pub std: Option<String>,
+    pub language: Option<Language>,
+    pub is_64bit: bool,
+    pub target_flags: Vec<String>,
+    pub lto: Lto,
+    pub gc_sections: bool,
+    pub sanitizers: Vec<String>,
+    pub debug_info: Option<u8>,
+    pub position_independent: bool,
+    pub pie: bool,
+    pub env: BTreeMap<String, String>,
+    pub compiler_launcher: Option<String>,
+    pub timings: Option<Arc<Timings>>,
+    pub reporter: Option<Arc<dyn Reporter>>,
+    pub full_rebuild: bool,
+    pub mtime_only: bool,
+    pub explain: bool,
+    pub color: bool,
+    pub json_depfiles: bool,
+    pub dry_run: bool,
+    pub timeout: Option<Duration>,
+    pub sysroot: Option<PathBuf>,
+}
+
+/// What a compiler invocation produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Emit {
+    Object,
+    Preprocessed,
+    Assembly,
 }
 
 #[derive(Debug)]
 pub struct InputFile {
-    tool_chain: ToolChain,
-    args: CompilerFlags,
-    includes: Vec<PathBuf>,
+    options: Arc<CompileOptions>,
     path: PathBuf,
     pub output_path: PathBuf,
-    full_rebuild: bool,
 }
 
 impl InputFile {
-    pub fn new(
-        path: PathBuf,
-        output_path: PathBuf,
-        tool_chain: ToolChain,
-        args: CompilerFlags,
-        includes: Vec<PathBuf>,
-        full_rebuild: bool,
-    ) -> Self {
+    const MSVC_INCLUDE_NOTE: &'static str = "Note: including file:";
+
+    pub fn new(path: PathBuf, output_path: PathBuf, options: Arc<CompileOptions>) -> Self {
         Self {
-            tool_chain,
-            args,
+            options,
             path,
             output_path,
-            includes,
-            full_rebuild,
         }
     }
 
-    pub async fn compile(&self) -> Result<OutputFile> {
-        if !self.should_recompile()? {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Moves the object into `dir` under a name derived from the compile key, so every graph
+    /// compiling this file identically agrees on a single object.
+    pub fn shared_in(self, dir: &Path) -> Self {
+        let hash = blake3::hash(self.compile_key().as_bytes()).to_hex();
+        let stem = self
+            .output_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let ext = self
+            .output_path
+            .extension()
+            .unwrap_or_default()
+            .to_string_lossy();
+        let output_path = dir.join(format!("{stem}-{}.{ext}", &hash[..16]));
+        Self {
+            output_path,
+            ..self
+        }
+    }
+
+    pub fn options(&self) -> &Arc<CompileOptions> {
+        &self.options
+    }
+
+    pub async fn compile(&self, progress: &Progress) -> Result<OutputFile, BuildError> {
+        let launcher = self.options.compiler_launcher.as_deref();
+        if self.options.dry_run {
+            println!("{}", self.command_with(launcher, Emit::Object).display());
             return Ok(OutputFile {
                 path: self.output_path.clone(),
+                language: self.language(),
             });
         }
-
-        let mut cmd = Command::new(self.tool_chain.compiler());
-        if self.tool_chain == ToolChain::Zig {
-            cmd.arg("cc");
+        match self.should_recompile()? {
+            Decision::UpToDate => {
+                if self.options.explain {
+                    tracing::info!("up to date: `{}`", self.path.display());
+                }
+                progress.finish(&self.path, false);
+                return Ok(OutputFile {
+                    path: self.output_path.clone(),
+                    language: self.language(),
+                });
+            }
+            Decision::Rebuild(reason) if self.options.explain => {
+                tracing::info!("recompiling `{}`: {reason}", self.path.display());
+            }
+            Decision::Rebuild(_) => {}
         }
 
-        self.append_input_file(&mut cmd);
-        self.append_output_file(&mut cmd);
-        self.append_args(&mut cmd);
-        self.append_includes(&mut cmd);
-
-        tracing::info!("[Compiling]: {}", self.path.display());
+        let mut cmd = self.command_with(launcher, Emit::Object);
+        // added here rather than in `command` so it never changes the recorded flags
+        if self.options.color
+            && let Some(flag) = self.options.tool_chain.color_flag()
+        {
+            cmd.arg(flag);
+        }
         tracing::debug!("[Compiling]: Command = {}", cmd.display());
-        let out = cmd
-            .spawn()
-            .context(format!("failed to spawn process: {:?}", cmd.as_std()))?
-            .wait()
-            .await;
+        let keep = usize::from(launcher.is_some())
+            + usize::from(self.options.tool_chain == ToolChain::Zig);
+        let mut cmd = response_file::apply(
+            cmd,
+            keep,
+            &self.output_path.with_extension("rsp"),
+            &self.options.tool_chain,
+        )
+        .await?;
+        cmd.kill_on_drop(true);
+        // diagnostics are buffered so parallel compiles never interleave them
+        cmd.stdout(std::process::Stdio::piped());
+        cmd.stderr(std::process::Stdio::piped());
+        let start = Instant::now();
+        self.report(&Message::CompileStarted { file: &self.path });
+        let mut process = crate::spawn(&mut cmd, "compiler")?;
+        let stdout = process.stdout.take();
+        let stderr = process.stderr.take();
+        let read_stdout = async {
+            let mut headers = Vec::new();
+            let mut output = String::new();
+            if let Some(stdout) = stdout {
+                let mut lines = BufReader::new(stdout).lines();
+                while let Some(line) = lines.next_line().await? {
+                    match line.strip_prefix(Self::MSVC_INCLUDE_NOTE) {
+                        Some(header) => headers.push(PathBuf::from(header.trim())),
+                        None => {
+                            output.push_str(&line);
+                            output.push('\n');
+                        }
+                    }
+                }
+            }
+            Ok::<_, std::io::Error>((headers, output))
+        };
+        let read_stderr = async {
+            let mut output = String::new();
+            if let Some(mut stderr) = stderr {
+                stderr.read_to_string(&mut output).await?;
+            }
+            Ok::<_, std::io::Error>(output)
+        };
+        // the pipes only close once the compiler exits, so reading them is part of the wait
+        let run = async {
+            let (stdout, stderr) = tokio::join!(read_stdout, read_stderr);
+            (stdout, stderr, process.wait().await)
+        };
+        let finished = match self.options.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run).await.ok(),
+            None => Some(run.await),
+        };
+        let Some((stdout, stderr, out)) = finished else {
+            self.report(&Message::CompileFinished {
+                file: &self.path,
+                success: false,
+                seconds: start.elapsed().as_secs_f64(),
+            });
+            process.kill().await?;
+            return Err(BuildError::Timeout {
+                what: format!("compilation of `{}`", self.path.display()),
+                secs: self.options.timeout.unwrap_or_default().as_secs(),
+            });
+        };
+        let (headers, mut diagnostics) = stdout?;
+        diagnostics.push_str(&stderr?);
+        if let Some(timings) = &self.options.timings {
+            timings.record(Step::Compile, self.path.clone(), start.elapsed());
+        }
+        self.report_diagnostics(&diagnostics);
+        self.report(&Message::CompileFinished {
+            file: &self.path,
+            success: matches!(&out, Ok(status) if status.success()),
+            seconds: start.elapsed().as_secs_f64(),
+        });
         match out {
             Ok(out) if !out.success() => {
-                return Err(anyhow::anyhow!(
-                    "failed to compile `{}`; compilation aborted",
-                    self.path.display()
-                ));
+                return Err(BuildError::CompileFailed {
+                    file: self.path.clone(),
+                    stderr: diagnostics,
+                });
             }
             Err(e) => {
                 return Err(anyhow::anyhow!(
                     "failed to compile `{}`; compilation aborted: {}",
                     self.path.display(),
                     e
-                ));
+                )
+                .into());
             }
             _ => {}
         }
 
+        // assembling plain `.s` files runs no preprocessor and leaves no depfile
+        if self.options.tool_chain == ToolChain::Msvc || !self.depfile_path().exists() {
+            self.write_depfile(&headers).await?;
+        }
+        if self.options.json_depfiles {
+            self.write_json_depfile().await?;
+        }
+        tokio::fs::write(self.fingerprint_path(), self.fingerprint()).await?;
+        tokio::fs::write(self.flags_path(), self.command().quoted()).await?;
+        progress.finish(&self.path, true);
+
         Ok(OutputFile {
             path: self.output_path.clone(),
+            language: self.language(),
         })
     }
 
-    fn append_input_file(&self, cmd: &mut Command) {
-        let input = self.path.display().to_string();
-        cmd.args([self.tool_chain.compiler_input_flag(), input.as_str()]);
+    /// Prints the buffered compiler output as one block, every line prefixed
+    /// with the source it belongs to.
+    fn report_diagnostics(&self, diagnostics: &str) {
+        if diagnostics.trim().is_empty() {
+            return;
+        }
+        if self.options.reporter.is_some() {
+            let message = strip_ansi(diagnostics);
+            self.report(&Message::Diagnostic {
+                file: &self.path,
+                message: &message,
+            });
+            return;
+        }
+        let diagnostics = if self.options.color {
+            diagnostics.to_string()
+        } else {
+            strip_ansi(diagnostics)
+        };
+        let mut block = String::new();
+        diagnostics.lines().for_each(|line| {
+            block.push_str(&format!("[{}]: {line}\n", self.path.display()));
+        });
+        _ = std::io::stderr().lock().write_all(block.as_bytes());
+    }
+
+    fn report(&self, message: &Message) {
+        if let Some(reporter) = &self.options.reporter {
+            reporter.report(message);
+        }
+    }
+
+    /// The forced language of the graph, otherwise guessed from the extension.
+    /// Assembly sources are never forced to C or C++.
+    fn language(&self) -> Language {
+        match Language::from_path(&self.path) {
+            Language::Asm => Language::Asm,
+            guessed => self.options.language.unwrap_or(guessed),
+        }
+    }
+
+    pub fn compile_command(&self, directory: &Path) -> Result<CompileCommand> {
+        Ok(CompileCommand {
+            directory: directory.to_path_buf(),
+            arguments: self.command().arguments(),
+            command: None,
+            file: std::path::absolute(&self.path)?,
+        })
     }
 
-    fn append_output_file(&self, cmd: &mut Command) {
-        let output = self.output_path.display().to_string();
-        if self.tool_chain == ToolChain::Msvc {
-            cmd.arg(format!("/Fo{}", output));
+    /// Runs only the preprocessor with this file's flags, writing the result
+    /// to `output` or stdout.
+    pub async fn preprocess(&self, output: Option<&Path>) -> Result<()> {
+        let mut cmd = self.command_with(None, Emit::Preprocessed);
+        tracing::debug!("[Preprocessing]: Command = {}", cmd.display());
+        cmd.stdout(std::process::Stdio::piped());
+        cmd.stderr(std::process::Stdio::piped());
+        let out = crate::spawn(&mut cmd, "compiler")?
+            .wait_with_output()
+            .await?;
+        self.report_diagnostics(&String::from_utf8_lossy(&out.stderr));
+        if !out.status.success() {
+            return Err(anyhow::anyhow!(
+                "failed to preprocess `{}`",
+                self.path.display()
+            ));
+        }
+        match output {
+            Some(output) => tokio::fs::write(output, &out.stdout).await?,
+            None => std::io::stdout().lock().write_all(&out.stdout)?,
+        }
+        Ok(())
+    }
+
+    /// Compiles to an assembly listing next to the object and returns its path.
+    pub async fn emit_assembly(&self) -> Result<PathBuf> {
+        if let Some(dir) = self.output_path.parent() {
+            crate::create_dir_all(dir).await?;
+        }
+        let mut cmd = self.command_with(None, Emit::Assembly);
+        tracing::debug!("[Assembly]: Command = {}", cmd.display());
+        cmd.stdout(std::process::Stdio::piped());
+        cmd.stderr(std::process::Stdio::piped());
+        let out = crate::spawn(&mut cmd, "compiler")?
+            .wait_with_output()
+            .await?;
+        let mut diagnostics = String::from_utf8_lossy(&out.stdout).to_string();
+        diagnostics.push_str(&String::from_utf8_lossy(&out.stderr));
+        self.report_diagnostics(&diagnostics);
+        if !out.status.success() {
+            return Err(anyhow::anyhow!(
+                "failed to generate assembly for `{}`",
+                self.path.display()
+            ));
+        }
+        tracing::info!("[Assembly]: {}", self.assembly_path().display());
+        Ok(self.assembly_path())
+    }
+
+    fn assembly_path(&self) -> PathBuf {
+        match self.options.tool_chain {
+            ToolChain::Msvc => self.output_path.with_extension("asm"),
+            _ => self.output_path.with_extension("s"),
+        }
+    }
+
+    fn command(&self) -> Command {
+        self.command_with(None, Emit::Object)
+    }
+
+    /// Identifies the compile by source, flags, environment and toolchain but not by where
+    /// the object goes.
+    fn compile_key(&self) -> String {
+        let stem = self.output_path.with_extension("").display().to_string();
+        let mut key = self.command().quoted().replace(&stem, "");
+        for (name, value) in &self.options.env {
+            key.push_str(&format!("\n{name}={value}"));
+        }
+        key
+    }
+
+    /// Assembles the compile command, optionally prefixed by a launcher such as `ccache`.
+    fn command_with(&self, launcher: Option<&str>, emit: Emit) -> Command {
+        let language = self.language();
+        if language == Language::Asm && self.options.tool_chain == ToolChain::Msvc {
+            return self.masm_command();
+        }
+        let compiler = match language {
+            Language::C => self.options.tool_chain.compiler(),
+            Language::Cpp => self.options.tool_chain.cxx_compiler(),
+            Language::Asm => self.options.tool_chain.assembler(self.options.is_64bit),
+        };
+        let mut cmd = match launcher {
+            Some(launcher) => {
+                let mut cmd = Command::new(launcher);
+                cmd.arg(compiler);
+                cmd
+            }
+            None => Command::new(compiler),
+        };
+        cmd.envs(&self.options.env);
+        if self.options.tool_chain == ToolChain::Zig {
+            cmd.arg(language.zig_command());
+        }
+        if let Some(forced) = &self.options.language
+            && *forced == language
+        {
+            cmd.args(self.options.tool_chain.language_flags(forced));
+        }
+
+        self.append_input_file(&mut cmd, emit);
+        self.append_output_file(&mut cmd, emit);
+        self.append_args(&mut cmd);
+        self.append_includes(&mut cmd);
+        if emit == Emit::Object {
+            self.append_depfile(&mut cmd);
+        }
+        cmd
+    }
+
+    /// `ml64.exe`/`ml.exe` only understand a small subset of the `cl.exe` switches.
+    fn masm_command(&self) -> Command {
+        let mut cmd = Command::new(self.options.tool_chain.assembler(self.options.is_64bit));
+        cmd.envs(&self.options.env);
+        cmd.arg("/nologo");
+        cmd.arg(crate::path_arg("/Fo", &self.output_path));
+        if self.options.debug_info.is_some_and(|level| level > 0) {
+            cmd.arg("/Zi");
+        }
+        self.options.args.defines.iter().for_each(|define| {
+            cmd.arg(format!("/D{define}"));
+        });
+        self.append_includes(&mut cmd);
+        cmd.arg("/c").arg(&self.path);
+        cmd
+    }
+
+    fn append_input_file(&self, cmd: &mut Command, emit: Emit) {
+        let flag = match emit {
+            Emit::Object => self.options.tool_chain.compiler_input_flag(),
+            Emit::Preprocessed => self.options.tool_chain.preprocess_flag(),
+            Emit::Assembly => self.options.tool_chain.assembly_flag(),
+        };
+        cmd.arg(flag).arg(&self.path);
+    }
+
+    fn append_output_file(&self, cmd: &mut Command, emit: Emit) {
+        // the preprocessor writes to stdout
+        if emit == Emit::Preprocessed {
+            return;
+        }
+        // msvc writes the listing alongside the object, everyone else instead of it
+        if emit == Emit::Assembly && self.options.tool_chain != ToolChain::Msvc {
+            cmd.arg(self.options.tool_chain.compiler_output_flag())
+                .arg(self.assembly_path());
             return;
         }
-        cmd.args([self.tool_chain.compiler_output_flag(), output.as_str()]);
+        if emit == Emit::Assembly {
+            cmd.arg("/FA");
+            cmd.arg(crate::path_arg("/Fa", self.assembly_path()));
+        }
+        if self.options.tool_chain == ToolChain::Msvc {
+            cmd.arg(crate::path_arg("/Fo", &self.output_path));
+            return;
+        }
+        cmd.arg(self.options.tool_chain.compiler_output_flag())
+            .arg(&self.output_path);
     }
 
     fn append_args(&self, cmd: &mut Command) {
-        if self.tool_chain == ToolChain::Msvc {
+        // standards, sanitizers and warnings are meaningless to the assembler
+        let assembly = self.language() == Language::Asm;
+        if self.options.tool_chain == ToolChain::Msvc {
             cmd.arg("/nologo");
         }
-        self.args.warnings.iter().for_each(|warning| {
-            cmd.arg(format!(
-                "{}{}",
-                self.tool_chain.compiler_warning_flag(),
-                warning.to_string(&self.tool_chain),
-            ));
+        cmd.args(&self.options.target_flags);
+        cmd.args(
+            self.options
+                .tool_chain
+                .optimization_flags(&self.options.opt_level),
+        );
+        if let Some(flag) = self.options.tool_chain.lto_compile_flag(&self.options.lto) {
+            cmd.arg(flag);
+        }
+        if self.options.gc_sections && !assembly {
+            cmd.args(self.options.tool_chain.section_flags());
+        }
+        if self.options.tool_chain != ToolChain::Msvc {
+            if self.options.position_independent {
+                cmd.arg("-fPIC");
+            } else if self.options.pie {
+                cmd.arg("-fPIE");
+            }
+        }
+        if !assembly
+            && let Some(std) = &self.options.std
+            && let Some(flag) = self.options.tool_chain.std_flag(std)
+        {
+            cmd.arg(flag);
+        }
+        if let Some(level) = self.options.debug_info
+            && let Some(flag) = self.options.tool_chain.debug_info_flag(level)
+        {
+            cmd.arg(flag);
+        }
+        if !assembly
+            && let Some(flag) = self
+                .options
+                .tool_chain
+                .sanitize_flag(&self.options.sanitizers)
+        {
+            cmd.arg(flag);
+        }
+        let mut warnings = Vec::new();
+        let enabled = self
+            .options
+            .args
+            .warnings
+            .iter()
+            .filter_map(|warning| warning.enable_flag(&self.options.tool_chain));
+        let disabled = self
+            .options
+            .args
+            .no_warnings
+            .iter()
+            .filter_map(|warning| warning.disable_flag(&self.options.tool_chain));
+        // msvc maps several flags onto the same switch, e.g. `All` and `Extra` to `/W4`
+        enabled.chain(disabled).for_each(|flag| {
+            if !warnings.contains(&flag) {
+                warnings.push(flag);
+            }
         });
-        self.args.no_warnings.iter().for_each(|warning| {
+        if !assembly {
+            cmd.args(warnings);
+        }
+        self.options.args.defines.iter().for_each(|define| {
             cmd.arg(format!(
                 "{}{}",
-                self.tool_chain.compiler_no_warning_flag(),
-                warning.to_string(&self.tool_chain),
+                self.options.tool_chain.compiler_define_flag(),
+                define
             ));
         });
-        self.args.custom.iter().for_each(|flag| {
+        self.options.args.compile_args().for_each(|flag| {
             cmd.arg(flag);
         });
     }
 
+    /// Appends the search paths in the order the compiler searches them: project
+    /// `includes` (`-I`), then `system_includes` (`-isystem`), then the sysroot,
+    /// so a project header always shadows a system header of the same name.
     fn append_includes(&self, cmd: &mut Command) {
-        self.includes.iter().for_each(|include| {
-            let include = include.display().to_string();
-            cmd.args([self.tool_chain.compiler_include_flag(), include.as_str()]);
+        self.options.includes.iter().for_each(|include| {
+            cmd.arg(self.options.tool_chain.compiler_include_flag())
+                .arg(include);
+        });
+        if !self.options.system_includes.is_empty() {
+            let flag = self.options.tool_chain.system_include_flag();
+            self.options.system_includes.iter().for_each(|include| {
+                cmd.arg(flag).arg(include);
+            });
+            if self.options.tool_chain == ToolChain::Msvc {
+                // msvc only silences external headers once given a warning level for them
+                cmd.arg("/external:W0");
+            }
+        }
+        if self.options.tool_chain != ToolChain::Msvc
+            && let Some(sysroot) = &self.options.sysroot
+        {
+            cmd.arg(crate::path_arg("--sysroot=", sysroot));
+        }
+    }
+
+    fn append_depfile(&self, cmd: &mut Command) {
+        if self.options.tool_chain == ToolChain::Msvc {
+            cmd.arg("/showIncludes");
+            return;
+        }
+        cmd.args(["-MMD", "-MF"]).arg(self.depfile_path());
+    }
+
+    /// Path of the make-style dependency file written next to the object; it is
+    /// kept after the build so make and ninja can read it too.
+    pub fn depfile_path(&self) -> PathBuf {
+        self.output_path.with_extension("d")
+    }
+
+    /// Path of the JSON dependency file written next to the object.
+    pub fn json_depfile_path(&self) -> PathBuf {
+        self.output_path.with_extension("d.json")
+    }
+
+    /// Escapes `path` the way make expects it inside a rule.
+    fn make_escape(path: &Path) -> String {
+        path.display()
+            .to_string()
+            .replace('$', "$$")
+            .replace('#', "\\#")
+            .replace(' ', "\\ ")
+    }
+
+    async fn write_depfile(&self, headers: &[PathBuf]) -> Result<()> {
+        let mut rule = format!(
+            "{}: {}",
+            Self::make_escape(&self.output_path),
+            Self::make_escape(&self.path)
+        );
+        headers.iter().for_each(|header| {
+            rule.push_str(" \\\n  ");
+            rule.push_str(&Self::make_escape(header));
+        });
+        rule.push('\n');
+        tokio::fs::write(self.depfile_path(), rule).await?;
+        Ok(())
+    }
+
+    /// Mirrors the make-style depfile as `{ "output", "dependencies" }`.
+    async fn write_json_depfile(&self) -> Result<()> {
+        let depfile = tokio::fs::read_to_string(self.depfile_path()).await?;
+        let json = serde_json::json!({
+            "output": self.output_path,
+            "dependencies": Self::parse_depfile(&depfile),
         });
+        tokio::fs::write(
+            self.json_depfile_path(),
+            serde_json::to_string_pretty(&json)?,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Parses a make-style depfile and returns every prerequisite of the rule.
+    fn parse_depfile(contents: &str) -> Vec<PathBuf> {
+        let contents = contents.replace("\\\r\n", " ").replace("\\\n", " ");
+        let Some((_, prerequisites)) = contents.split_once(": ") else {
+            return Vec::new();
+        };
+        let mut paths = Vec::new();
+        let mut current = String::new();
+        let mut chars = prerequisites.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if matches!(chars.peek(), Some(' ' | '#')) => {
+                    current.extend(chars.next());
+                }
+                '$' if chars.peek() == Some(&'$') => {
+                    current.extend(chars.next());
+                }
+                c if c.is_whitespace() => {
+                    if !current.is_empty() {
+                        paths.push(PathBuf::from(std::mem::take(&mut current)));
+                    }
+                }
+                c => current.push(c),
+            }
+        }
+        if !current.is_empty() {
+            paths.push(PathBuf::from(current));
+        }
+        paths
+    }
+
+    fn fingerprint_path(&self) -> PathBuf {
+        self.output_path.with_extension("hash")
+    }
+
+    /// Hashes the compile command together with the contents of the source and
+    /// every header recorded in its depfile.
+    fn fingerprint(&self) -> String {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.command().quoted().as_bytes());
+        let mut dependencies = vec![self.path.clone()];
+        if let Ok(depfile) = std::fs::read_to_string(self.depfile_path()) {
+            dependencies.extend(Self::parse_depfile(&depfile));
+        }
+        for dependency in dependencies {
+            hasher.update(dependency.as_os_str().as_encoded_bytes());
+            if let Ok(contents) = std::fs::read(&dependency) {
+                hasher.update(&contents);
+            }
+        }
+        hasher.finalize().to_hex().to_string()
     }
 
-    fn should_recompile(&self) -> Result<bool> {
-        if self.full_rebuild {
-            return Ok(true);
+    /// Sidecar holding the compile command the object was last built with.
+    fn flags_path(&self) -> PathBuf {
+        self.output_path.with_extension("flags")
+    }
+
+    fn should_recompile(&self) -> Result<Decision> {
+        if self.options.full_rebuild {
+            return Ok(Decision::Rebuild(Reason::FullRebuild));
+        }
+        let Ok(flags) = std::fs::read_to_string(self.flags_path()) else {
+            return Ok(Decision::Rebuild(Reason::NoRecord));
+        };
+        if flags != self.command().quoted() {
+            return Ok(Decision::Rebuild(Reason::FlagsChanged));
+        }
+        if !self.options.mtime_only {
+            let json_depfile = self.options.json_depfiles.then(|| self.json_depfile_path());
+            let expected = [
+                Some(self.output_path.clone()),
+                Some(self.depfile_path()),
+                json_depfile,
+            ];
+            if let Some(missing) = expected.into_iter().flatten().find(|path| !path.exists()) {
+                return Ok(Decision::Rebuild(Reason::Missing(missing)));
+            }
+            let Ok(stored) = std::fs::read_to_string(self.fingerprint_path()) else {
+                return Ok(Decision::Rebuild(Reason::NoRecord));
+            };
+            if stored == self.fingerprint() {
+                return Ok(Decision::UpToDate);
+            }
+            // the hash can't tell which input changed; modification times usually can
+            return Ok(match self.newer_dependency()? {
+                Decision::UpToDate => Decision::Rebuild(Reason::ContentsChanged),
+                decision => decision,
+            });
         }
+        self.newer_dependency()
+    }
+
+    /// Compares the modification times of the source and every header in its
+    /// depfile against the object's.
+    fn newer_dependency(&self) -> Result<Decision> {
         let input_metadata = self.path.metadata()?;
         let Ok(output_metadata) = self.output_path.metadata() else {
-            return Ok(true);
+            return Ok(Decision::Rebuild(Reason::Missing(self.output_path.clone())));
         };
-        Ok(input_metadata.modified()? > output_metadata.modified()?)
+        let output_modified = output_metadata.modified()?;
+        if input_metadata.modified()? > output_modified {
+            return Ok(Decision::Rebuild(Reason::Newer(self.path.clone())));
+        }
+        let Ok(depfile) = std::fs::read_to_string(self.depfile_path()) else {
+            return Ok(Decision::Rebuild(Reason::Missing(self.depfile_path())));
+        };
+        for dependency in Self::parse_depfile(&depfile) {
+            let Ok(metadata) = dependency.metadata() else {
+                return Ok(Decision::Rebuild(Reason::Removed(dependency)));
+            };
+            if metadata.modified()? > output_modified {
+                return Ok(Decision::Rebuild(Reason::Newer(dependency)));
+            }
+        }
+        Ok(Decision::UpToDate)
+    }
+}
+
+/// Removes ANSI escape sequences so piped diagnostics stay plain text.
+fn strip_ansi(text: &str) -> String {
+    let mut plain = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            plain.push(c);
+            continue;
+        }
+        if chars.next() == Some('[') {
+            // parameters and intermediates run up to the final byte in `@..=~`
+            for c in chars.by_ref() {
+                if ('@'..='~').contains(&c) {
+                    break;
+                }
+            }
+        }
+    }
+    plain
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::WarningFlag;
+
+    fn options(tool_chain: ToolChain) -> CompileOptions {
+        CompileOptions {
+            tool_chain,
+            opt_level: OptimizationLevel::Debug,
+            args: CompilerFlags::default(),
+            includes: Vec::new(),
+            system_includes: Vec::new(),
+            std: None,
+            language: None,
+            is_64bit: true,
+            target_flags: Vec::new(),
+            lto: Lto::Off,
+            gc_sections: false,
+            sanitizers: Vec::new(),
+            debug_info: None,
+            position_independent: false,
+            pie: false,
+            env: BTreeMap::new(),
+            compiler_launcher: None,
+            timings: None,
+            reporter: None,
+            full_rebuild: false,
+            mtime_only: false,
+            explain: false,
+            color: false,
+            json_depfiles: false,
+            dry_run: false,
+            timeout: None,
+            sysroot: None,
+        }
+    }
+
+    fn input(source: &str, options: CompileOptions) -> InputFile {
+        let output = PathBuf::from(format!("obj/{source}.o"));
+        InputFile::new(PathBuf::from(source), output, Arc::new(options))
+    }
+
+    /// The compile command of `source`, program first.
+    fn args(source: &str, options: CompileOptions) -> Vec<String> {
+        input(source, options).command().arguments()
+    }
+
+    fn has(args: &[String], arg: &str) -> bool {
+        args.iter().any(|candidate| candidate == arg)
+    }
+
+    #[test]
+    fn optimization_level_is_passed_to_the_compiler() {
+        let release = CompileOptions {
+            opt_level: OptimizationLevel::Release,
+            ..options(ToolChain::Gcc)
+        };
+        let release = args("main.c", release);
+        assert!(has(&release, "-O2"), "{release:?}");
+        assert!(!has(&release, "-O0"), "{release:?}");
+
+        let debug = args("main.c", options(ToolChain::Gcc));
+        assert!(has(&debug, "-O0"), "{debug:?}");
+    }
+
+    #[test]
+    fn msvc_spells_optimization_levels_its_own_way() {
+        let chain = ToolChain::Msvc;
+        assert_eq!(chain.optimization_flags(&OptimizationLevel::Debug), ["/Od"]);
+        assert_eq!(chain.optimization_flags(&OptimizationLevel::O3), ["/O2"]);
+        assert_eq!(chain.optimization_flags(&OptimizationLevel::OSize), ["/Os"]);
+        assert_eq!(
+            ToolChain::Clang.optimization_flags(&OptimizationLevel::OSize),
+            ["-Os"]
+        );
+    }
+
+    /// Sets the modification time of `path` to `secs` seconds after the epoch.
+    fn set_modified(path: &Path, secs: u64) {
+        let time = std::time::UNIX_EPOCH + Duration::from_secs(secs);
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(time)
+            .unwrap();
+    }
+
+    #[test]
+    fn depfile_prerequisites_are_parsed_across_continuations() {
+        let depfile = "obj/main.c.o: src/main.c \\\n  include/a\\ b.h \\\r\n  include/c.h\n";
+        assert_eq!(
+            InputFile::parse_depfile(depfile),
+            [
+                PathBuf::from("src/main.c"),
+                PathBuf::from("include/a b.h"),
+                PathBuf::from("include/c.h"),
+            ]
+        );
+    }
+
+    #[test]
+    fn edited_header_triggers_recompile() {
+        let dir = crate::scratch_dir("edited_header");
+        let source = dir.join("main.c");
+        let header = dir.join("util.h");
+        let file = InputFile::new(
+            source.clone(),
+            dir.join("main.c.o"),
+            Arc::new(CompileOptions {
+                mtime_only: true,
+                ..options(ToolChain::Gcc)
+            }),
+        );
+        std::fs::write(&source, "#include \"util.h\"\n").unwrap();
+        std::fs::write(&header, "").unwrap();
+        std::fs::write(&file.output_path, "").unwrap();
+        let depfile = format!(
+            "{}: {} {}\n",
+            file.output_path.display(),
+            source.display(),
+            header.display()
+        );
+        std::fs::write(file.depfile_path(), depfile).unwrap();
+        std::fs::write(file.flags_path(), file.command().quoted()).unwrap();
+        set_modified(&source, 100);
+        set_modified(&header, 100);
+        set_modified(&file.output_path, 200);
+        assert_eq!(file.should_recompile().unwrap(), Decision::UpToDate);
+
+        set_modified(&header, 300);
+        assert_eq!(
+            file.should_recompile().unwrap(),
+            Decision::Rebuild(Reason::Newer(header))
+        );
+    }
+
+    #[test]
+    fn defines_use_each_toolchains_flag() {
+        let with_defines = |tool_chain| {
+            let mut options = options(tool_chain);
+            options.args.defines = vec!["DEBUG".to_string(), "LEVEL=2".to_string()];
+            args("main.c", options)
+        };
+        let gcc = with_defines(ToolChain::Gcc);
+        assert!(has(&gcc, "-DDEBUG") && has(&gcc, "-DLEVEL=2"), "{gcc:?}");
+        let msvc = with_defines(ToolChain::Msvc);
+        assert!(has(&msvc, "/DDEBUG") && has(&msvc, "/DLEVEL=2"), "{msvc:?}");
+    }
+
+    #[test]
+    fn std_is_selected_per_toolchain() {
+        let with_std = |tool_chain, std: &str| {
+            args(
+                "main.c",
+                CompileOptions {
+                    std: Some(std.to_string()),
+                    ..options(tool_chain)
+                },
+            )
+        };
+        assert!(has(&with_std(ToolChain::Clang, "c11"), "-std=c11"));
+        assert!(has(&with_std(ToolChain::Msvc, "c17"), "/std:c17"));
+        // msvc has no switch for older standards, so none is passed
+        let msvc = with_std(ToolChain::Msvc, "c99");
+        assert!(!msvc.iter().any(|arg| arg.starts_with("/std")), "{msvc:?}");
+    }
+
+    /// `main.c` in `dir` with every record a successful compile leaves behind.
+    fn compiled(dir: &Path, options: CompileOptions) -> InputFile {
+        let source = dir.join("main.c");
+        let file = InputFile::new(source.clone(), dir.join("main.c.o"), Arc::new(options));
+        std::fs::write(&source, "int main(void) { return 0; }\n").unwrap();
+        std::fs::write(&file.output_path, "").unwrap();
+        let depfile = format!("{}: {}\n", file.output_path.display(), source.display());
+        std::fs::write(file.depfile_path(), depfile).unwrap();
+        std::fs::write(file.flags_path(), file.command().quoted()).unwrap();
+        std::fs::write(file.fingerprint_path(), file.fingerprint()).unwrap();
+        set_modified(&source, 100);
+        set_modified(&file.output_path, 200);
+        file
+    }
+
+    #[test]
+    fn content_hashes_ignore_touched_sources() {
+        let dir = crate::scratch_dir("content_hashes");
+        let file = compiled(&dir, options(ToolChain::Gcc));
+        assert_eq!(file.should_recompile().unwrap(), Decision::UpToDate);
+
+        // a checkout or `touch` changes the time but not the contents
+        set_modified(&file.path, 300);
+        assert_eq!(file.should_recompile().unwrap(), Decision::UpToDate);
+
+        std::fs::write(&file.path, "int main(void) { return 1; }\n").unwrap();
+        set_modified(&file.path, 100);
+        assert_eq!(
+            file.should_recompile().unwrap(),
+            Decision::Rebuild(Reason::ContentsChanged)
+        );
+    }
+
+    #[test]
+    fn launcher_wraps_the_compiler_but_not_the_recorded_command() {
+        let file = input("main.c", options(ToolChain::Clang));
+        let launched = file.command_with(Some("ccache"), Emit::Object).arguments();
+        assert_eq!(launched[..4], ["ccache", "clang", "-c", "main.c"]);
+        // switching launchers must not invalidate every object
+        assert_eq!(file.command().arguments()[0], "clang");
+    }
+
+    #[test]
+    fn changed_flags_trigger_recompile() {
+        let dir = crate::scratch_dir("changed_flags");
+        let file = compiled(&dir, options(ToolChain::Gcc));
+        let mut options = options(ToolChain::Gcc);
+        options.args.defines.push("NDEBUG".to_string());
+        let changed = InputFile::new(
+            file.path.clone(),
+            file.output_path.clone(),
+            Arc::new(options),
+        );
+        assert_eq!(
+            changed.should_recompile().unwrap(),
+            Decision::Rebuild(Reason::FlagsChanged)
+        );
+    }
+
+    #[test]
+    fn env_is_set_on_the_compiler() {
+        let mut options = options(ToolChain::Gcc);
+        options
+            .env
+            .insert("CBUILD_TEST".to_string(), "1".to_string());
+        let cmd = input("main.c", options).command();
+        assert!(
+            cmd.as_std()
+                .get_envs()
+                .any(|(key, value)| key == "CBUILD_TEST" && value == Some("1".as_ref()))
+        );
+    }
+
+    #[test]
+    fn msvc_gets_its_own_diagnostic_switches() {
+        let mut msvc = options(ToolChain::Msvc);
+        msvc.args.warnings = vec![WarningFlag::All, WarningFlag::Extra, WarningFlag::Error];
+        msvc.args.no_warnings = vec![WarningFlag::DeprecatedDeclarations, WarningFlag::Pedantic];
+        let msvc = args("main.c", msvc);
+        assert_eq!(msvc.iter().filter(|arg| *arg == "/W4").count(), 1);
+        assert!(has(&msvc, "/WX") && has(&msvc, "/wd4996"));
+        assert!(!msvc.iter().any(|arg| arg.starts_with("-W")));
+
+        let mut gcc = options(ToolChain::Gcc);
+        gcc.args.warnings = vec![WarningFlag::All, WarningFlag::Extra];
+        gcc.args.no_warnings = vec![WarningFlag::DeprecatedDeclarations];
+        let gcc = args("main.c", gcc);
+        assert!(
+            has(&gcc, "-Wall") && has(&gcc, "-Wextra") && has(&gcc, "-Wno-deprecated-declarations")
+        );
+    }
+
+    #[test]
+    fn cpp_sources_use_the_cpp_driver() {
+        assert_eq!(args("main.cpp", options(ToolChain::Gcc))[0], "g++");
+        assert_eq!(args("main.c", options(ToolChain::Clang))[0], "clang");
+
+        let mut forced = options(ToolChain::Gcc);
+        forced.language = Some(Language::Cpp);
+        let forced = args("main.c", forced);
+        assert_eq!(forced[0], "g++");
+        assert!(forced.windows(2).any(|pair| pair == ["-x", "c++"]));
+
+        let zig = args("main.cc", options(ToolChain::Zig));
+        assert_eq!(zig[..2], ["zig", "c++"]);
+    }
+
+    #[test]
+    fn assembly_skips_c_only_flags() {
+        let mut gcc = options(ToolChain::Gcc);
+        gcc.std = Some("c11".to_string());
+        gcc.language = Some(Language::Cpp);
+        gcc.args.warnings = vec![WarningFlag::All];
+        let gcc = args("start.S", gcc);
+        assert_eq!(gcc[0], "gcc");
+        assert!(
+            !gcc.iter()
+                .any(|arg| arg == "-std=c11" || arg == "-Wall" || arg == "-x")
+        );
+
+        let mut msvc = options(ToolChain::Msvc);
+        msvc.args.defines = vec!["X64".to_string()];
+        let msvc = args("start.asm", msvc);
+        assert_eq!(
+            msvc,
+            [
+                "ml64.exe",
+                "/nologo",
+                "/Foobj/start.asm.o",
+                "/DX64",
+                "/c",
+                "start.asm"
+            ]
+        );
+        let mut x86 = options(ToolChain::Msvc);
+        x86.is_64bit = false;
+        assert_eq!(args("start.asm", x86)[0], "ml.exe");
+    }
+
+    #[test]
+    fn pic_wins_over_pie() {
+        let mut pie = options(ToolChain::Gcc);
+        pie.pie = true;
+        assert!(has(&args("main.c", pie.clone()), "-fPIE"));
+        pie.position_independent = true;
+        let pic = args("main.c", pie);
+        assert!(has(&pic, "-fPIC") && !has(&pic, "-fPIE"));
+
+        let mut msvc = options(ToolChain::Msvc);
+        msvc.position_independent = true;
+        assert!(!args("main.c", msvc).iter().any(|arg| arg.starts_with("-f")));
+    }
+
+    #[tokio::test]
+    async fn diagnostics_are_reported_per_file() {
+        let dir = crate::scratch_dir("diagnostics");
+        let source = dir.join("main.c");
+        std::fs::write(&source, "int main(void) { int unused; return 0; }\n").unwrap();
+        let recorder = Arc::new(crate::Recorder::default());
+        let mut options = options(ToolChain::Gcc);
+        options.args.warnings = vec![WarningFlag::All];
+        options.reporter = Some(recorder.clone());
+        let file = InputFile::new(source.clone(), dir.join("main.c.o"), Arc::new(options));
+        file.compile(&Progress::new(1, true)).await.unwrap();
+
+        let messages = recorder.0.lock().unwrap();
+        let diagnostics = messages
+            .iter()
+            .filter(|message| message["reason"] == "diagnostic")
+            .collect::<Vec<_>>();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["file"], source.display().to_string());
+        assert!(
+            diagnostics[0]["message"]
+                .as_str()
+                .unwrap()
+                .contains("unused")
+        );
+    }
+
+    #[tokio::test]
+    async fn preprocessing_writes_the_expanded_source() {
+        let dir = crate::scratch_dir("preprocess");
+        let source = dir.join("main.c");
+        std::fs::write(&source, "int answer(void) { return ANSWER; }\n").unwrap();
+        let mut gcc = options(ToolChain::Gcc);
+        gcc.args.defines = vec!["ANSWER=42".to_string()];
+        let file = InputFile::new(source, dir.join("main.c.o"), Arc::new(gcc));
+        file.preprocess(Some(&dir.join("main.i"))).await.unwrap();
+        assert!(
+            std::fs::read_to_string(dir.join("main.i"))
+                .unwrap()
+                .contains("return 42;")
+        );
+        assert!(!file.output_path.exists());
+
+        let msvc = input("main.c", options(ToolChain::Msvc))
+            .command_with(None, Emit::Preprocessed)
+            .arguments();
+        assert!(has(&msvc, "/E") && !msvc.iter().any(|arg| arg.starts_with("/Fo") || arg == "/c"));
+    }
+
+    #[tokio::test]
+    async fn assembly_listings_land_next_to_the_object() {
+        let dir = crate::scratch_dir("emit_assembly");
+        let source = dir.join("main.c");
+        std::fs::write(&source, "int answer(void) { return 42; }\n").unwrap();
+        let object = dir.join("obj/main.c.o");
+        let file = InputFile::new(source, object.clone(), Arc::new(options(ToolChain::Gcc)));
+        let listing = file.emit_assembly().await.unwrap();
+        assert_eq!(listing, dir.join("obj/main.c.s"));
+        assert!(
+            std::fs::read_to_string(&listing)
+                .unwrap()
+                .contains("answer")
+        );
+        assert!(!object.exists());
+
+        let msvc = input("main.c", options(ToolChain::Msvc))
+            .command_with(None, Emit::Assembly)
+            .arguments();
+        assert!(
+            has(&msvc, "/FA") && has(&msvc, "/Faobj/main.c.asm") && has(&msvc, "/Foobj/main.c.o")
+        );
+    }
+
+    #[test]
+    fn link_flags_stay_off_compiles() {
+        let mut flags = options(ToolChain::Gcc);
+        flags.args.custom = vec!["-pthread".to_string()];
+        flags.args.compile_flags = vec!["-fno-exceptions".to_string()];
+        flags.args.link_flags = vec!["-Wl,--as-needed".to_string()];
+        let compile = args("main.c", flags);
+        assert!(has(&compile, "-pthread") && has(&compile, "-fno-exceptions"));
+        assert!(!has(&compile, "-Wl,--as-needed"));
+    }
+
+    #[test]
+    fn colors_are_forced_or_stripped() {
+        assert_eq!(
+            strip_ansi("\x1b[01;35mwarning:\x1b[m unused \x1b[1mx\x1b[0m"),
+            "warning: unused x"
+        );
+        assert_eq!(strip_ansi("plain [text]"), "plain [text]");
+
+        // coloring is added at spawn time, so toggling it never forces a recompile
+        let mut color = options(ToolChain::Gcc);
+        color.color = true;
+        assert_eq!(
+            args("main.c", color),
+            args("main.c", options(ToolChain::Gcc))
+        );
+        assert_eq!(
+            ToolChain::Gcc.color_flag(),
+            Some("-fdiagnostics-color=always")
+        );
+        assert_eq!(ToolChain::Msvc.color_flag(), None);
+    }
+
+    #[test]
+    fn system_includes_follow_regular_includes() {
+        let mut gcc = options(ToolChain::Gcc);
+        gcc.includes = vec![PathBuf::from("include")];
+        gcc.system_includes = vec![PathBuf::from("third_party/include")];
+        let gcc = args("main.c", gcc);
+        let position = |arg: &str| gcc.iter().position(|candidate| candidate == arg).unwrap();
+        assert_eq!(gcc[position("-isystem") + 1], "third_party/include");
+        assert!(position("include") < position("-isystem"));
+
+        let mut msvc = options(ToolChain::Msvc);
+        msvc.system_includes = vec![PathBuf::from("third_party/include")];
+        let msvc = args("main.c", msvc);
+        let position = msvc.iter().position(|arg| arg == "/external:I").unwrap();
+        assert_eq!(
+            msvc[position + 1..position + 3],
+            ["third_party/include", "/external:W0"]
+        );
+
+        assert!(!has(
+            &args("main.c", options(ToolChain::Msvc)),
+            "/external:W0"
+        ));
+    }
+
+    #[test]
+    fn msvc_output_paths_stay_one_argument() {
+        let msvc = args("my file.c", options(ToolChain::Msvc));
+        assert!(has(&msvc, "/Foobj/my file.c.o"), "{msvc:?}");
+        let gcc = args("my file.c", options(ToolChain::Gcc));
+        let position = gcc.iter().position(|arg| arg == "-o").unwrap();
+        assert_eq!(gcc[position + 1], "obj/my file.c.o");
+    }
+
+    #[test]
+    fn sysroot_is_passed_to_compilers_but_msvc() {
+        let mut gcc = options(ToolChain::Gcc);
+        gcc.sysroot = Some(PathBuf::from("/opt/sysroot"));
+        assert!(has(&args("main.c", gcc), "--sysroot=/opt/sysroot"));
+        let mut msvc = options(ToolChain::Msvc);
+        msvc.sysroot = Some(PathBuf::from("/opt/sysroot"));
+        assert!(
+            !args("main.c", msvc)
+                .iter()
+                .any(|arg| arg.starts_with("--sysroot"))
+        );
+    }
+
+    #[test]
+    fn make_escaped_paths_parse_back() {
+        let header = Path::new("my $dir/#1 a.h");
+        let escaped = InputFile::make_escape(header);
+        assert_eq!(escaped, r"my\ $$dir/\#1\ a.h");
+        let depfile = format!("main.o: main.c \\\n  {escaped}\n");
+        assert_eq!(
+            InputFile::parse_depfile(&depfile),
+            [PathBuf::from("main.c"), header.to_path_buf()]
+        );
+    }
+
+    #[tokio::test]
+    async fn json_depfiles_mirror_the_make_ones() {
+        let dir = crate::scratch_dir("json_depfiles");
+        std::fs::write(dir.join("config.h"), "#define ANSWER 42\n").unwrap();
+        let source = dir.join("main.c");
+        std::fs::write(
+            &source,
+            "#include \"config.h\"\nint main(void) { return ANSWER - 42; }\n",
+        )
+        .unwrap();
+        let mut gcc = options(ToolChain::Gcc);
+        gcc.json_depfiles = true;
+        let file = InputFile::new(source.clone(), dir.join("main.c.o"), Arc::new(gcc));
+        file.compile(&Progress::new(1, true)).await.unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(file.json_depfile_path()).unwrap())
+                .unwrap();
+        assert_eq!(json["output"], serde_json::json!(dir.join("main.c.o")));
+        assert_eq!(
+            json["dependencies"],
+            serde_json::json!([source, dir.join("config.h")])
+        );
+        assert!(file.depfile_path().is_file());
+
+        std::fs::remove_file(file.json_depfile_path()).unwrap();
+        assert_ne!(file.should_recompile().unwrap(), Decision::UpToDate);
+    }
+
+    #[test]
+    fn include_paths_follow_the_search_order() {
+        let mut gcc = options(ToolChain::Gcc);
+        gcc.includes = vec![PathBuf::from("include")];
+        gcc.system_includes = vec![PathBuf::from("third_party")];
+        gcc.sysroot = Some(PathBuf::from("/opt/sysroot"));
+        let gcc = args("main.c", gcc);
+        let position = |arg: &str| gcc.iter().position(|candidate| candidate == arg).unwrap();
+        assert!(position("include") < position("third_party"));
+        assert!(position("third_party") < position("--sysroot=/opt/sysroot"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hung_compilers_are_killed_after_the_timeout() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = crate::scratch_dir("compile_timeout");
+        let compiler = dir.join("hung-cc");
+        std::fs::write(&compiler, "#!/bin/sh\nexec sleep 30\n").unwrap();
+        std::fs::set_permissions(&compiler, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let source = dir.join("main.c");
+        std::fs::write(&source, "int main(void) { return 0; }\n").unwrap();
+        let tool_chain =
+            serde_json::from_value(serde_json::json!({ "compiler": compiler, "linker": "gcc" }))
+                .unwrap();
+        let mut options = options(tool_chain);
+        options.timeout = Some(Duration::from_millis(200));
+        let file = InputFile::new(source.clone(), dir.join("main.c.o"), Arc::new(options));
+
+        let start = Instant::now();
+        let error = file.compile(&Progress::new(1, true)).await.unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(
+            matches!(&error, BuildError::Timeout { what, .. } if what.contains("main.c")),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn every_rebuild_names_its_reason() {
+        let dir = crate::scratch_dir("reasons");
+        let file = compiled(&dir, options(ToolChain::Gcc));
+        let mut full = options(ToolChain::Gcc);
+        full.full_rebuild = true;
+        let full = InputFile::new(file.path.clone(), file.output_path.clone(), Arc::new(full));
+        let reason = |decision: Decision| match decision {
+            Decision::Rebuild(reason) => reason.to_string(),
+            Decision::UpToDate => panic!("expected a rebuild"),
+        };
+        assert_eq!(
+            reason(full.should_recompile().unwrap()),
+            "full rebuild requested"
+        );
+
+        std::fs::write(
+            file.depfile_path(),
+            format!(
+                "main.c.o: {} {}\n",
+                file.path.display(),
+                dir.join("gone.h").display()
+            ),
+        )
+        .unwrap();
+        std::fs::write(&file.path, "int main(void) { return 1; }\n").unwrap();
+        set_modified(&file.path, 100);
+        let removed = format!("`{}` was removed", dir.join("gone.h").display());
+        assert_eq!(reason(file.should_recompile().unwrap()), removed);
+
+        std::fs::remove_file(&file.output_path).unwrap();
+        let missing = format!("`{}` does not exist", file.output_path.display());
+        assert_eq!(reason(file.should_recompile().unwrap()), missing);
+
+        std::fs::remove_file(file.flags_path()).unwrap();
+        assert_eq!(
+            reason(file.should_recompile().unwrap()),
+            "no record of a previous build"
+        );
+    }
+
+    #[test]
+    fn gc_sections_split_compiled_code_but_not_assembly() {
+        let mut gcc = options(ToolChain::Gcc);
+        gcc.gc_sections = true;
+        let c = args("main.c", gcc.clone());
+        assert!(has(&c, "-ffunction-sections") && has(&c, "-fdata-sections"));
+        assert!(!has(&args("start.S", gcc), "-ffunction-sections"));
+
+        let mut msvc = options(ToolChain::Msvc);
+        msvc.gc_sections = true;
+        let msvc = args("main.c", msvc);
+        assert!(has(&msvc, "/Gy") && has(&msvc, "/Gw"));
+        assert!(!has(
+            &args("main.c", options(ToolChain::Gcc)),
+            "-ffunction-sections"
+        ));
     }
 }
@@ -1,11 +1,11 @@
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use std::{path::{Path, PathBuf}};
+use std::{collections::{BTreeMap, BTreeSet}, path::{Component, Path, PathBuf}, sync::{Arc, LazyLock}, time::{Duration, Instant}};
 use tokio::{
-    fs::{self, read_dir}, process::Command, task::JoinSet
+    fs::{self, read_dir}, process::{Child, Command}, sync::{Mutex, OwnedSemaphorePermit, Semaphore}, task::JoinSet
 };
 
-use crate::{file::{InputFile, OutputFile}, CommandExt};
+use crate::{error::BuildError, file::{CompileCommand, CompileOptions, Decision, InputFile, ObjectCache, OutputFile, Reason}, progress::Progress, reporter::{Message, Reporter}, response_file, timings::{Step, Timings}, CommandExt};
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Os {
@@ -23,6 +23,8 @@ impl Os {
             Self::Linux
         }else if cfg!(target_os = "macos") {
             Self::MacOs
+        }else if cfg!(unix) {
+            Self::UnixLike
         }else {
             unimplemented!("Os::Current")
         }
@@ -40,6 +42,21 @@ pub enum OptimizationLevel {
     OSize,
 }
 
+impl OptimizationLevel {
+    /// Name of the cache subdirectory holding this level's objects.
+    pub fn profile(&self) -> &str {
+        match self {
+            Self::Debug => "debug",
+            Self::Release => "release",
+            Self::O0 => "o0",
+            Self::O1 => "o1",
+            Self::O2 => "o2",
+            Self::O3 => "o3",
+            Self::OSize => "osize",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum Target {
     WindowX86,
@@ -48,6 +65,37 @@ pub enum Target {
     LinuxX64,
 }
 
+impl Target {
+    pub fn os(&self) -> Os {
+        match self {
+            Self::WindowX86 | Self::WindowsX64 => Os::Window,
+            Self::LinuxX86 | Self::LinuxX64 => Os::Linux,
+        }
+    }
+
+    pub fn is_64bit(&self) -> bool {
+        matches!(self, Self::WindowsX64 | Self::LinuxX64)
+    }
+
+    pub fn zig_triple(&self) -> &str {
+        match self {
+            Self::WindowX86 => "x86-windows-gnu",
+            Self::WindowsX64 => "x86_64-windows-gnu",
+            Self::LinuxX86 => "x86-linux-gnu",
+            Self::LinuxX64 => "x86_64-linux-gnu",
+        }
+    }
+
+    pub fn clang_triple(&self) -> &str {
+        match self {
+            Self::WindowX86 => "i686-pc-windows-gnu",
+            Self::WindowsX64 => "x86_64-pc-windows-gnu",
+            Self::LinuxX86 => "i686-unknown-linux-gnu",
+            Self::LinuxX64 => "x86_64-unknown-linux-gnu",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum BinaryType {
     Executable,
@@ -55,19 +103,68 @@ pub enum BinaryType {
     StaticLib,
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Language {
+    C,
+    #[serde(alias = "C++")]
+    Cpp,
+    /// Hand-written assembly, only ever detected from the extension.
+    #[serde(skip)]
+    Asm,
+}
+
+impl Language {
+    /// Guesses the language from the source extension, defaulting to C.
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("cpp" | "cc" | "cxx" | "c++" | "C") => Self::Cpp,
+            Some("s" | "S" | "asm") => Self::Asm,
+            _ => Self::C,
+        }
+    }
+
+    /// Subcommand of `zig` driving this language.
+    pub fn zig_command(&self) -> &str {
+        match self {
+            Self::C | Self::Asm => "cc",
+            Self::Cpp => "c++",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum ToolChain {
     Gcc,
     Clang,
     Msvc,
     Zig,
+    /// Any other gcc-like compiler; `flags` overrides the gcc conventions for
+    /// compilers that spell them differently.
     #[serde(untagged)]
     Custom {
         compiler: String,
         linker: String,
+        #[serde(flatten)]
+        flags: Box<CustomFlags>,
     },
 }
 
+/// Overrides of a `Custom` toolchain; unset flags fall back to gcc's.
+#[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CustomFlags {
+    obj_file_ext: Option<String>,
+    input_flag: Option<String>,
+    output_flag: Option<String>,
+    include_flag: Option<String>,
+    define_flag: Option<String>,
+    archiver: Option<String>,
+    linker_output_flag: Option<String>,
+    link_lib_flag: Option<String>,
+    link_dir_flag: Option<String>,
+    /// Sysroot used by every graph building with this toolchain unless the graph sets its own.
+    sysroot: Option<PathBuf>,
+}
+
 impl ToolChain {
     pub fn platform_default() -> Self {
         if cfg!(target_os = "windows") {
@@ -76,19 +173,129 @@ impl ToolChain {
             ToolChain::Gcc
         }else if cfg!(target_os = "macos") {
             ToolChain::Clang
+        }else if cfg!(any(target_os = "freebsd", target_os = "openbsd")) {
+            // the BSDs ship clang as their system compiler
+            ToolChain::Clang
+        }else if cfg!(unix) {
+            ToolChain::Gcc
         }else {
             unimplemented!("ToolChain::platform_default()")
         }
     }
 
+    /// Version of the compiler, probed once per compiler and cached.
+    pub async fn version(&self) -> Option<String> {
+        static VERSIONS: LazyLock<Mutex<BTreeMap<String, Option<String>>>> = LazyLock::new(Default::default);
+        let mut versions = VERSIONS.lock().await;
+        if let Some(version) = versions.get(self.compiler()) {
+            return version.clone();
+        }
+        let version = self.probe_version().await;
+        versions.insert(self.compiler().to_string(), version.clone());
+        version
+    }
+
+    /// Logs the compiler's version the first time any graph builds with it, so a
+    /// script mixing toolchains reports each of them exactly once.
+    pub async fn log_version(&self) {
+        static LOGGED: LazyLock<Mutex<BTreeSet<String>>> = LazyLock::new(Default::default);
+        if !LOGGED.lock().await.insert(self.compiler().to_string()) {
+            return;
+        }
+        match self.version().await {
+            Some(version) => tracing::info!("using {} {version}", self.compiler()),
+            None => tracing::debug!("could not determine the version of {}", self.compiler()),
+        }
+    }
+
+    async fn probe_version(&self) -> Option<String> {
+        let mut cmd = Command::new(self.compiler());
+        match self {
+            Self::Zig => {
+                cmd.arg("version");
+            }
+            // cl.exe prints its banner when run without arguments
+            Self::Msvc => {}
+            _ => {
+                cmd.arg("--version");
+            }
+        }
+        let out = cmd.output().await.ok()?;
+        let banner = if out.stdout.is_empty() { out.stderr } else { out.stdout };
+        let banner = String::from_utf8_lossy(&banner);
+        let line = banner.lines().find(|line| !line.trim().is_empty())?;
+        Some(Self::parse_version(line).unwrap_or_else(|| line.trim().to_string()))
+    }
+
+    /// Picks the version number out of the first line of a version banner,
+    /// e.g. `gcc (GCC) 13.2.0`, `clang version 17.0.6` or `0.13.0`.
+    pub fn parse_version(line: &str) -> Option<String> {
+        let tokens = line.split_whitespace().collect::<Vec<_>>();
+        if let Some(index) = tokens.iter().position(|token| token.eq_ignore_ascii_case("version"))
+            && let Some(version) = tokens.get(index + 1)
+        {
+            return Some(version.to_string());
+        }
+        tokens
+            .iter()
+            .rev()
+            .find(|token| token.starts_with(|c: char| c.is_ascii_digit()))
+            .map(|token| token.to_string())
+    }
+
+    fn custom_flag(&self, flag: impl Fn(&CustomFlags) -> &Option<String>) -> Option<&str> {
+        match self {
+            Self::Custom { flags, .. } => flag(flags).as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The sysroot configured on a `Custom` toolchain.
+    pub fn sysroot(&self) -> Option<&Path> {
+        match self {
+            Self::Custom { flags, .. } => flags.sysroot.as_deref(),
+            _ => None,
+        }
+    }
+
     pub fn obj_file_ext(&self) -> &str {
+        if let Some(obj_file_ext) = self.custom_flag(|flags| &flags.obj_file_ext) {
+            return obj_file_ext;
+        }
         match self {
             Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "o",
             Self::Msvc => "obj"
         }
     }
 
+    pub fn preprocess_flag(&self) -> &str {
+        match self {
+            Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-E",
+            Self::Msvc => "/E",
+        }
+    }
+
+    /// Forces colored diagnostics even though the compiler's output is piped.
+    pub fn color_flag(&self) -> Option<&str> {
+        match self {
+            Self::Gcc => Some("-fdiagnostics-color=always"),
+            Self::Clang | Self::Zig => Some("-fcolor-diagnostics"),
+            Self::Msvc | Self::Custom { .. } => None,
+        }
+    }
+
+    pub fn assembly_flag(&self) -> &str {
+        match self {
+            Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-S",
+            // `/FA` is added next to the output, `/c` still produces the object
+            Self::Msvc => "/c",
+        }
+    }
+
     pub fn compiler_input_flag(&self) -> &str {
+        if let Some(input_flag) = self.custom_flag(|flags| &flags.input_flag) {
+            return input_flag;
+        }
         match self {
             Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-c",
             Self::Msvc => "/c"
@@ -96,6 +303,9 @@ impl ToolChain {
     }
 
     pub fn compiler_output_flag(&self) -> &str {
+        if let Some(output_flag) = self.custom_flag(|flags| &flags.output_flag) {
+            return output_flag;
+        }
         match self {
             Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-o",
             Self::Msvc => "/Fo"
@@ -103,12 +313,44 @@ impl ToolChain {
     }
 
     pub fn compiler_include_flag(&self) -> &str {
+        if let Some(include_flag) = self.custom_flag(|flags| &flags.include_flag) {
+            return include_flag;
+        }
         match self {
             Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-I",
             Self::Msvc => "/I"
         }
     }
 
+    /// Include flag for directories whose headers should not produce warnings.
+    pub fn system_include_flag(&self) -> &str {
+        match self {
+            Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-isystem",
+            Self::Msvc => "/external:I",
+        }
+    }
+
+    pub fn compiler_define_flag(&self) -> &str {
+        if let Some(define_flag) = self.custom_flag(|flags| &flags.define_flag) {
+            return define_flag;
+        }
+        match self {
+            Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-D",
+            Self::Msvc => "/D"
+        }
+    }
+
+    pub fn std_flag(&self, std: &str) -> Option<String> {
+        match self {
+            Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => Some(format!("-std={std}")),
+            Self::Msvc if matches!(std, "c11" | "c17") => Some(format!("/std:{std}")),
+            Self::Msvc => {
+                tracing::warn!("msvc does not support `{std}`; only `c11` and `c17` are accepted");
+                None
+            }
+        }
+    }
+
     pub fn compiler_warning_flag(&self) -> &str {
         match self {
             Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-W",
@@ -123,6 +365,84 @@ impl ToolChain {
         }
     }
 
+    pub fn optimization_flags(&self, level: &OptimizationLevel) -> &[&str] {
+        use OptimizationLevel::*;
+        match (self, level) {
+            (Self::Msvc, Debug | O0) => &["/Od"],
+            (Self::Msvc, O1) => &["/O1"],
+            (Self::Msvc, Release | O2 | O3) => &["/O2"],
+            (Self::Msvc, OSize) => &["/Os"],
+            (_, Debug | O0) => &["-O0"],
+            (_, O1) => &["-O1"],
+            (_, Release | O2) => &["-O2"],
+            (_, O3) => &["-O3"],
+            (_, OSize) => &["-Os"],
+        }
+    }
+
+    pub fn target_flags(&self, target: &Target) -> Vec<String> {
+        let arch = if target.is_64bit() { "-m64" } else { "-m32" };
+        match self {
+            Self::Gcc | Self::Custom { .. } => vec![arch.to_string()],
+            Self::Clang => vec![format!("--target={}", target.clang_triple()), arch.to_string()],
+            Self::Zig => vec!["-target".to_string(), target.zig_triple().to_string()],
+            Self::Msvc => Vec::new(),
+        }
+    }
+
+    pub fn debug_info_flag(&self, level: u8) -> Option<String> {
+        match self {
+            Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => Some(format!("-g{level}")),
+            Self::Msvc => (level > 0).then(|| "/Zi".to_string()),
+        }
+    }
+
+    pub fn sanitize_flag(&self, sanitizers: &[String]) -> Option<String> {
+        if sanitizers.is_empty() {
+            return None;
+        }
+        match self {
+            Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => {
+                Some(format!("-fsanitize={}", sanitizers.join(",")))
+            }
+            Self::Msvc => {
+                sanitizers
+                    .iter()
+                    .filter(|sanitizer| *sanitizer != "address")
+                    .for_each(|sanitizer| tracing::warn!("msvc does not support the `{sanitizer}` sanitizer"));
+                sanitizers
+                    .iter()
+                    .any(|sanitizer| sanitizer == "address")
+                    .then(|| "/fsanitize=address".to_string())
+            }
+        }
+    }
+
+    pub fn lto_compile_flag(&self, lto: &Lto) -> Option<&str> {
+        match (self, lto) {
+            (_, Lto::Off) => None,
+            (Self::Msvc, _) => Some("/GL"),
+            (_, Lto::Thin) => Some("-flto=thin"),
+            (_, Lto::Full) => Some("-flto"),
+        }
+    }
+
+    /// Puts every function and global in its own section so the linker can drop unused ones.
+    pub fn section_flags(&self) -> &[&str] {
+        match self {
+            Self::Msvc => &["/Gy", "/Gw"],
+            _ => &["-ffunction-sections", "-fdata-sections"],
+        }
+    }
+
+    /// Objects compiled with LTO only link when the linker is told as well.
+    pub fn lto_link_flag(&self, lto: &Lto) -> Option<&str> {
+        match (self, lto) {
+            (Self::Msvc, Lto::Thin | Lto::Full) => Some("/LTCG"),
+            (chain, lto) => chain.lto_compile_flag(lto),
+        }
+    }
+
     pub fn compiler(&self) -> &str {
         match self {
             Self::Gcc => "gcc",
@@ -133,19 +453,79 @@ impl ToolChain {
         }
     }
 
+    pub fn cxx_compiler(&self) -> &str {
+        match self {
+            Self::Gcc => "g++",
+            Self::Clang => "clang++",
+            Self::Msvc => "cl.exe",
+            Self::Zig => "zig",
+            Self::Custom { compiler, .. } => compiler,
+        }
+    }
+
+    /// Flags forcing every source to be compiled as `language`.
+    pub fn language_flags(&self, language: &Language) -> &[&str] {
+        match (self, language) {
+            (Self::Msvc, Language::C) => &["/TC"],
+            (Self::Msvc, Language::Cpp) => &["/TP"],
+            (_, Language::C) => &["-x", "c"],
+            (_, Language::Cpp) => &["-x", "c++"],
+            (_, Language::Asm) => &[],
+        }
+    }
+
+    /// Program assembling `.s`/`.S`/`.asm` sources. Everything but msvc lets
+    /// the compiler driver invoke the assembler.
+    pub fn assembler(&self, is_64bit: bool) -> &str {
+        match self {
+            Self::Msvc if is_64bit => "ml64.exe",
+            Self::Msvc => "ml.exe",
+            chain => chain.compiler(),
+        }
+    }
+
     pub fn linker(&self, bin_type: &BinaryType) -> &str {
         match (self, bin_type) {
-            (Self::Gcc, BinaryType::Executable) => "gcc",
-            (Self::Clang, BinaryType::Executable) => "clang",
-            (Self::Msvc, BinaryType::Executable) => "link.exe",
-            (Self::Msvc, BinaryType::StaticLib) => "lib.exe",
-            (Self::Zig, BinaryType::Executable) => "zig",
+            (Self::Gcc, BinaryType::Executable | BinaryType::DynLib) => "gcc",
+            (Self::Clang, BinaryType::Executable | BinaryType::DynLib) => "clang",
+            (Self::Msvc, BinaryType::Executable | BinaryType::DynLib) => "link.exe",
+            (Self::Zig, BinaryType::Executable | BinaryType::DynLib) => "zig",
+            (chain, BinaryType::StaticLib) => chain.archiver(),
             (Self::Custom { linker, .. }, _) => linker,
-            (chain, typ) => unimplemented!("linker: {chain:?}, {typ:?}"),
+        }
+    }
+
+    /// Linker driver pulling in the C++ runtime.
+    pub fn cxx_linker(&self, bin_type: &BinaryType) -> &str {
+        match (self, bin_type) {
+            (Self::Gcc, BinaryType::Executable | BinaryType::DynLib) => "g++",
+            (Self::Clang, BinaryType::Executable | BinaryType::DynLib) => "clang++",
+            (chain, bin_type) => chain.linker(bin_type),
+        }
+    }
+
+    pub fn archiver(&self) -> &str {
+        if let Some(archiver) = self.custom_flag(|flags| &flags.archiver) {
+            return archiver;
+        }
+        match self {
+            Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "ar",
+            Self::Msvc => "lib.exe",
+        }
+    }
+
+    pub fn shared_lib_flags(&self) -> &[&str] {
+        match self {
+            Self::Gcc | Self::Clang | Self::Custom { .. } => &["-shared", "-fPIC"],
+            Self::Zig => &["-shared"],
+            Self::Msvc => &["/DLL"],
         }
     }
 
     pub fn linker_output_flag(&self) -> &str {
+        if let Some(linker_output_flag) = self.custom_flag(|flags| &flags.linker_output_flag) {
+            return linker_output_flag;
+        }
         match self {
             Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-o",
             Self::Msvc => "/OUT:"
@@ -153,23 +533,118 @@ impl ToolChain {
     }
 
     pub fn linker_link_lib(&self) -> &str {
+        if let Some(link_lib_flag) = self.custom_flag(|flags| &flags.link_lib_flag) {
+            return link_lib_flag;
+        }
         match self {
             Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-l",
-            Self::Msvc => unimplemented!("msvc: linker_link_dir_flag()"),
+            // `link.exe` takes libraries by file name, see `link_lib_arg`
+            Self::Msvc => "",
+        }
+    }
+
+    /// The linker argument for library `lib`, e.g. `-lfoo` or `foo.lib`.
+    pub fn link_lib_arg(&self, lib: &str) -> String {
+        let arg = format!("{}{lib}", self.linker_link_lib());
+        if *self == Self::Msvc && Path::new(lib).extension().is_none() {
+            return format!("{arg}.lib");
         }
+        arg
     }
 
     pub fn linker_link_dir_flag(&self) -> &str {
+        if let Some(link_dir_flag) = self.custom_flag(|flags| &flags.link_dir_flag) {
+            return link_dir_flag;
+        }
         match self {
             Self::Gcc | Self::Clang | Self::Zig | Self::Custom { .. } => "-L",
-            Self::Msvc => unimplemented!("msvc: linker_link_dir_flag()"),
+            Self::Msvc => "/LIBPATH:",
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Where `linker_args` go in the link command, relative to the libraries.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum LinkerArgsPosition {
+    #[default]
+    BeforeLibs,
+    AfterLibs,
+}
+
+/// `map_file = true` derives `<output>.map`, a string picks the path.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum MapFile {
+    Enabled(bool),
+    Path(PathBuf),
+}
+
+/// One shell command or several, run in order.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Hook {
+    Command(String),
+    Commands(Vec<String>),
+}
+
+impl Hook {
+    fn commands(&self) -> &[String] {
+        match self {
+            Self::Command(command) => std::slice::from_ref(command),
+            Self::Commands(commands) => commands,
+        }
+    }
+}
+
+/// Sources only compiled when building for the matching OS.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PlatformFiles {
+    #[serde(default)]
+    pub linux: Vec<PathBuf>,
+    #[serde(default)]
+    pub windows: Vec<PathBuf>,
+    #[serde(default)]
+    pub macos: Vec<PathBuf>,
+}
+
+impl PlatformFiles {
+    fn for_os(&self, os: &Os) -> &[PathBuf] {
+        match os {
+            Os::Linux => &self.linux,
+            Os::Window => &self.windows,
+            Os::MacOs => &self.macos,
+            Os::UnixLike => &[],
+        }
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut PathBuf> {
+        self.linux.iter_mut().chain(&mut self.windows).chain(&mut self.macos)
+    }
+}
+
+/// `bin_dir = true` links into `<cache_dir>/bin`, a string picks the directory.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum BinDir {
+    Enabled(bool),
+    Path(PathBuf),
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Lto {
+    #[default]
+    Off,
+    Thin,
+    Full,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum WarningFlag {
+    /// Every warning is an error.
     Error,
+    /// Only the named warning is an error, e.g. `unused-variable`; msvc takes
+    /// the warning number instead, e.g. `4101`.
+    ErrorOn(String),
     Pedantic,
     Extra,
     All,
@@ -177,11 +652,38 @@ pub enum WarningFlag {
 }
 
 impl WarningFlag {
+    /// Switch enabling this warning, or `None` if the toolchain has no equivalent.
+    pub fn enable_flag(&self, tool_chain: &ToolChain) -> Option<String> {
+        use ToolChain::Msvc;
+        match (self, tool_chain) {
+            (Self::All | Self::Extra, Msvc) => Some("/W4".to_string()),
+            (Self::Error, Msvc) => Some("/WX".to_string()),
+            (Self::ErrorOn(code), Msvc) => code.parse::<u32>().ok().map(|code| format!("/we{code}")),
+            (Self::ErrorOn(name), _) => Some(format!("{}error={name}", tool_chain.compiler_warning_flag())),
+            (Self::Pedantic, Msvc) => Some("/Wall".to_string()),
+            (Self::DeprecatedDeclarations, Msvc) => Some("/w14996".to_string()),
+            (_, _) => Some(format!("{}{}", tool_chain.compiler_warning_flag(), self.to_string(tool_chain))),
+        }
+    }
+
+    /// Switch disabling this warning, or `None` if the toolchain has no equivalent.
+    pub fn disable_flag(&self, tool_chain: &ToolChain) -> Option<String> {
+        use ToolChain::Msvc;
+        match (self, tool_chain) {
+            (Self::Error, Msvc) => Some("/WX-".to_string()),
+            (Self::DeprecatedDeclarations, Msvc) => Some("/wd4996".to_string()),
+            (_, Msvc) => None,
+            (Self::ErrorOn(name), _) => Some(format!("{}error={name}", tool_chain.compiler_no_warning_flag())),
+            (_, _) => Some(format!("{}{}", tool_chain.compiler_no_warning_flag(), self.to_string(tool_chain))),
+        }
+    }
+
     pub fn to_string(&self, tool_chain: &ToolChain) -> &str {
         use ToolChain::Msvc;
         match (self, tool_chain) {
             (_, Msvc) => "",
             (Self::Error, _) => "error",
+            (Self::ErrorOn(name), _) => name,
             (Self::Pedantic, _) => "pedantic",
             (Self::Extra, _) => "extra",
             (Self::All, _) => "all",
@@ -196,8 +698,49 @@ pub struct CompilerFlags {
     pub warnings: Vec<WarningFlag>,
     #[serde(default = "Vec::new")]
     pub no_warnings: Vec<WarningFlag>,
+    /// Deprecated: passed to both compiles and links; use `compile_flags` or `link_flags`.
     #[serde(default = "Vec::new")]
     pub custom: Vec<String>,
+    /// Flags passed only to the compiler.
+    #[serde(default = "Vec::new")]
+    pub compile_flags: Vec<String>,
+    /// Flags passed only to the linker.
+    #[serde(default = "Vec::new")]
+    pub link_flags: Vec<String>,
+    /// Preprocessor defines, either `NAME` or `NAME=value`.
+    #[serde(default = "Vec::new")]
+    pub defines: Vec<String>,
+}
+
+impl CompilerFlags {
+    /// Layers `other` on top of these flags: warning lists are unioned while
+    /// defines and the custom, compile and link flags are appended after the existing ones.
+    pub fn merge(&mut self, other: &CompilerFlags) {
+        other.warnings.iter().for_each(|warning| {
+            if !self.warnings.contains(warning) {
+                self.warnings.push(warning.clone());
+            }
+        });
+        other.no_warnings.iter().for_each(|warning| {
+            if !self.no_warnings.contains(warning) {
+                self.no_warnings.push(warning.clone());
+            }
+        });
+        self.defines.extend(other.defines.iter().cloned());
+        self.custom.extend(other.custom.iter().cloned());
+        self.compile_flags.extend(other.compile_flags.iter().cloned());
+        self.link_flags.extend(other.link_flags.iter().cloned());
+    }
+
+    /// Every flag a compile receives: `custom` followed by `compile_flags`.
+    pub fn compile_args(&self) -> impl Iterator<Item = &String> {
+        self.custom.iter().chain(&self.compile_flags)
+    }
+
+    /// Every flag a link receives: `custom` followed by `link_flags`.
+    pub fn link_args(&self) -> impl Iterator<Item = &String> {
+        self.custom.iter().chain(&self.link_flags)
+    }
 }
 
 fn default_src() -> PathBuf {
@@ -212,182 +755,1546 @@ fn default_output() -> PathBuf {
     PathBuf::from("a")
 }
 
+/// Files matching `pattern`, which may use `**` for any depth and `{a,b}`
+/// for alternatives, in the order they were found.
+pub fn expand_glob(pattern: &str) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    for pattern in expand_braces(pattern) {
+        for path in glob::glob(&pattern)?.filter_map(|entry| entry.ok()) {
+            if path.is_file() && !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+    }
+    Ok(paths)
+}
+
+/// Expands the first `{a,b}` group of `pattern` and recurses into the results,
+/// so nested and repeated groups are expanded too.
+fn expand_braces(pattern: &str) -> Vec<String> {
+    let Some(open) = pattern.find('{') else {
+        return vec![pattern.to_string()];
+    };
+    let mut bounds = vec![open];
+    let mut depth = 0;
+    let mut close = None;
+    for (i, c) in pattern.char_indices().skip_while(|(i, _)| *i < open) {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    close = Some(i);
+                    break;
+                }
+            }
+            ',' if depth == 1 => bounds.push(i),
+            _ => {}
+        }
+    }
+    let Some(close) = close else {
+        return vec![pattern.to_string()];
+    };
+    bounds.push(close);
+    let (prefix, suffix) = (&pattern[..open], &pattern[close + 1..]);
+    bounds
+        .windows(2)
+        .flat_map(|bound| expand_braces(&format!("{prefix}{}{suffix}", &pattern[bound[0] + 1..bound[1]])))
+        .collect()
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Graph {
     tool_chain: ToolChain,
     opt_level: OptimizationLevel,
+    /// Selects the graph on the command line; defaults to the file name of `output`.
+    name: Option<String>,
     #[serde(rename = "type", default = "default_binary_type")]
     typ: BinaryType,
     files: Vec<PathBuf>,
+    /// Added to `files` when building for the OS they are listed under.
+    #[serde(default)]
+    platform_files: PlatformFiles,
+    /// Prebuilt objects linked after the compiled ones, as they are.
+    #[serde(default = "Vec::new")]
+    objects: Vec<PathBuf>,
     #[serde(default = "default_output")]
     output: PathBuf,
+    /// Appends the profile to the output name, e.g. `app-release`, so
+    /// profiles don't overwrite each other's binaries.
+    #[serde(default)]
+    output_suffix_by_profile: bool,
+    /// Keeps library outputs exactly as named instead of prefixing them with
+    /// `lib` off Windows.
+    #[serde(default)]
+    no_lib_prefix: bool,
+    /// Links into this directory instead of next to `output`; installing then
+    /// copies the binary to `output`.
+    bin_dir: Option<BinDir>,
+    /// Shell commands run before any source is collected or compiled.
+    pre_build: Option<Hook>,
+    /// Shell commands run after a successful link.
+    post_build: Option<Hook>,
     #[serde(default = "default_src")]
     src_dir: PathBuf,
     #[serde(default = "Vec::new")]
     includes: Vec<PathBuf>,
+    /// Include directories whose headers never produce warnings, e.g. third-party code.
+    #[serde(default = "Vec::new")]
+    system_includes: Vec<PathBuf>,
     #[serde(default = "Vec::new")]
     pub lib_paths: Vec<String>,
     #[serde(default = "Vec::new")]
     pub libs: Vec<String>,
+    /// Static libraries linked in full, so objects nothing references (e.g.
+    /// self-registering constructors) are kept.
+    #[serde(default = "Vec::new")]
+    whole_archive_libs: Vec<String>,
+    /// Passed to the linker verbatim and in order, e.g. `-Wl,--start-group`; never
+    /// to compiles or archivers.
+    #[serde(default = "Vec::new")]
+    linker_args: Vec<String>,
+    #[serde(default)]
+    linker_args_position: LinkerArgsPosition,
+    /// macOS frameworks linked with `-framework`; ignored on other targets.
+    #[serde(default = "Vec::new")]
+    frameworks: Vec<String>,
+    /// Directories searched for `frameworks`.
+    #[serde(default = "Vec::new")]
+    framework_paths: Vec<PathBuf>,
+    /// Packages whose cflags and libs are queried through `pkg-config`.
+    #[serde(default = "Vec::new")]
+    pub pkg_config: Vec<String>,
+    /// Runtime library search paths, passed through verbatim (e.g. `$ORIGIN/../lib`).
+    #[serde(default = "Vec::new")]
+    pub rpaths: Vec<String>,
     #[serde(default = "CompilerFlags::default")]
     args: CompilerFlags,
+    /// Extra flags for sources matching a glob or path prefix. They are merged
+    /// on top of `args`, in key order when several entries match.
+    #[serde(default = "BTreeMap::new")]
+    overrides: BTreeMap<String, CompilerFlags>,
     excludes: Option<Vec<PathBuf>>,
+    std: Option<String>,
+    target: Option<Target>,
+    /// Explicit `arch-os-abi` triple for `zig cc`; takes precedence over `target`.
+    zig_target: Option<String>,
+    /// Root of the target's headers and libraries, passed as `--sysroot` to compiles and links.
+    sysroot: Option<PathBuf>,
+    #[serde(default = "Vec::new")]
+    sanitizers: Vec<String>,
+    /// Compile position-independent code; always on for `DynLib`.
+    #[serde(default)]
+    pic: bool,
+    /// Build a position-independent executable.
+    #[serde(default)]
+    pie: bool,
+    /// Linker map written while linking.
+    map_file: Option<MapFile>,
+    /// Link-time optimization; `Thin` is only supported by clang.
+    #[serde(default)]
+    lto: Lto,
+    /// Strip symbols from the linked binary, unless it is built with debug info.
+    #[serde(default)]
+    strip: bool,
+    /// Compile into per-function and per-data sections and let the linker drop
+    /// the unreferenced ones.
+    #[serde(default)]
+    gc_sections: bool,
+    /// Debug info level from 0 to 3; defaults to on for `Debug`/`O0` and off otherwise.
+    debug_info: Option<u8>,
+    /// Extra environment for every compiler, linker and archiver process.
+    #[serde(default = "BTreeMap::new")]
+    pub env: BTreeMap<String, String>,
+    /// Compiles every source as C or C++ instead of guessing from the extension.
+    language: Option<Language>,
+    /// Compiles batches of this many sources as a single translation unit.
+    unity_batch_size: Option<usize>,
+    /// Program that wraps every compiler invocation, e.g. `ccache`.
+    compiler_launcher: Option<String>,
+    /// Install root receiving `bin/`, `lib/` and `include/`.
+    pub install_prefix: Option<PathBuf>,
+    /// Headers or header directories installed into `include/` for libraries.
+    #[serde(default = "Vec::new")]
+    install_headers: Vec<PathBuf>,
+    /// Directory for build artifacts, `.cargoc` by default.
+    pub cache_dir: Option<PathBuf>,
     #[serde(skip)]
     pub full_rebuild: bool,
+    /// Decide recompilation by modification times instead of content hashes.
+    #[serde(skip)]
+    pub mtime_only: bool,
+    /// Log the reason behind every recompile and relink decision.
+    #[serde(skip)]
+    pub explain: bool,
+    /// Print every compile, link and hook command instead of running it.
+    #[serde(skip)]
+    pub dry_run: bool,
+    /// How often a link that failed on a locked file is retried.
+    #[serde(skip)]
+    pub link_retries: u32,
+    /// How long a single compile or link may run before it is killed.
+    #[serde(skip)]
+    pub timeout: Option<Duration>,
+    /// Compile every file before reporting failures instead of stopping at the first.
+    #[serde(skip)]
+    pub keep_going: bool,
+    /// Suppresses the per-file compile lines.
+    #[serde(skip)]
+    pub quiet: bool,
+    /// Keep compiler diagnostics colored; they are stripped of colors otherwise.
+    #[serde(skip)]
+    pub color: bool,
+    /// Also write every object's dependencies as JSON next to its make-style depfile.
+    #[serde(skip)]
+    pub json_depfiles: bool,
+    /// Limits how many compiler and linker processes may run at once; shared by
+    /// every graph of a build so concurrent graphs respect one limit.
+    #[serde(skip)]
+    pub jobs: Option<Arc<Semaphore>>,
+    /// Records how long each compile and link step takes when set.
+    #[serde(skip)]
+    pub timings: Option<Arc<Timings>>,
+    /// Receives compile, link and diagnostic events when set.
+    #[serde(skip)]
+    pub reporter: Option<Arc<dyn Reporter>>,
+    /// Shares compiled objects with the other graphs of the same build when set.
+    #[serde(skip)]
+    pub object_cache: Option<Arc<ObjectCache>>,
 }
 
 impl Graph {
-    const CACHE_DIR: &'static str = ".cargoc";
+    pub const CACHE_DIR: &'static str = ".cargoc";
     const OBJ_DIR: &'static str = "obj";
-    //const BIN_DIR: &'static str = "bin";
+    const BIN_DIR: &'static str = "bin";
+    const LTO_CACHE_DIR: &'static str = "lto";
+    const SHARED_OBJ_DIR: &'static str = "shared";
 
-    pub async fn build(&self) -> Result<PathBuf> {
-        if let Ok(exists) = fs::try_exists(Self::CACHE_DIR).await && !exists {
-            fs::create_dir(Self::CACHE_DIR).await?;
+    pub async fn build(&self) -> Result<PathBuf, BuildError> {
+        if !self.pkg_config.is_empty() {
+            return Box::pin(self.with_pkg_config().await?.build()).await;
         }
-        let obj_dir = Path::new(Self::CACHE_DIR).join(Self::OBJ_DIR);
-        if let Ok(exists) = fs::try_exists(&obj_dir).await && !exists {
-            fs::create_dir(&obj_dir).await?;
+        self.validate()?;
+        let obj_dir = self.obj_dir();
+        if !self.dry_run {
+            crate::create_dir_all(&obj_dir).await?;
         }
+        if !self.dry_run && self.lto() == Lto::Thin {
+            crate::create_dir_all(self.lto_cache_dir()).await?;
+        }
+        if let Some(hook) = &self.pre_build {
+            self.run_hook("pre_build", hook).await?;
+        }
+        // after the hook, which may generate sources; a dry run only shows the commands
+        if !self.dry_run {
+            self.validate_tools().await?;
+        }
+        self.tool_chain.log_version().await;
 
-        let mut input_files = Vec::with_capacity(self.files.len());
-
-        let files = if let Some(excludes) = &self.excludes {
-            self.files.iter().filter(|file| !excludes.contains(file)).collect::<Vec<_>>()
-        }else {
-            self.files.iter().collect()
-        };
-
-        for file in files {
-            if file.is_dir() {
-                input_files.extend(Self::read_dir(file).await?)
-            } else {
-                input_files.push(file.clone());
+        let mut input_files = self.input_files().await?;
+        // linkers and archivers given nothing fail with far less helpful errors
+        if input_files.is_empty() && self.objects.is_empty() {
+            return Err(anyhow::anyhow!("no source files to compile for output `{}`", self.output.display()).into());
+        }
+        if let Some(batch_size) = self.unity_batch_size {
+            input_files = self.unity_files(input_files, batch_size).await?;
+        }
+        for file in &input_files {
+            if !self.dry_run && let Some(dir) = file.output_path.parent() {
+                crate::create_dir_all(dir).await?;
             }
         }
-        let input_files = input_files
-            .into_iter()
-            .map(|file| {
-                let output = file.strip_prefix(&self.src_dir).unwrap_or(&file);
-                let output = Path::new(Self::CACHE_DIR).join(Self::OBJ_DIR).join(output).with_extension(self.tool_chain.obj_file_ext());
+        let progress = Arc::new(Progress::new(input_files.len(), self.quiet));
+        let mut set = JoinSet::new();
+        input_files.into_iter().enumerate().for_each(|(index, file)| {
+            let jobs = self.jobs.clone();
+            let progress = progress.clone();
+            let objects = self.object_cache.clone();
+            set.spawn(async move {
+                let _permit = match jobs {
+                    Some(jobs) => Some(jobs.acquire_owned().await?),
+                    None => None,
+                };
+                let output = match objects {
+                    Some(objects) => objects.compile(&file, &progress).await,
+                    None => file.compile(&progress).await,
+                };
+                output.map(|file| (index, file)).map_err(anyhow::Error::from)
+            });
+        });
+        let mut output_files = Vec::with_capacity(set.len());
+        let mut errors = Vec::new();
+        while let Some(result) = set.join_next().await {
+            match result.map_err(anyhow::Error::from)? {
+                Ok(file) => output_files.push(file),
+                Err(e) if self.keep_going => errors.push(BuildError::from(e)),
+                Err(e) => {
+                    // aborted compiles kill their compiler process on drop
+                    set.abort_all();
+                    return Err(e.into());
+                }
+            }
+        }
+        // a lone failure keeps its own variant
+        match errors.len() {
+            0 => {}
+            1 => return Err(errors.remove(0)),
+            _ => return Err(BuildError::Multiple(errors)),
+        }
+        progress.report();
+        // link in declaration order rather than completion order
+        output_files.sort_by_key(|(index, _)| *index);
+        let mut output_files = output_files.into_iter().map(|(_, file)| file).collect::<Vec<_>>();
+        output_files.extend(self.objects.iter().map(|object| OutputFile {
+            path: object.clone(),
+            language: Language::C,
+        }));
+
+        let program = self.link(&output_files).await?;
+        if let Some(hook) = &self.post_build {
+            self.run_hook("post_build", hook).await?;
+        }
+
+        Ok(program)
+    }
+
+    /// Runs every command of `hook` through the platform shell with the graph's `env`.
+    async fn run_hook(&self, name: &str, hook: &Hook) -> Result<()> {
+        for command in hook.commands() {
+            let mut cmd = if cfg!(windows) {
+                let mut cmd = Command::new("cmd");
+                cmd.arg("/C");
+                cmd
+            } else {
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c");
+                cmd
+            };
+            cmd.arg(command);
+            cmd.envs(&self.env);
+            cmd.kill_on_drop(true);
+            if self.dry_run {
+                println!("{}", cmd.display());
+                continue;
+            }
+            tracing::info!("[Running]: {name} `{command}`");
+            let status = crate::spawn(&mut cmd, "shell")?.wait().await?;
+            if !status.success() {
+                return Err(anyhow::anyhow!("{name} hook `{command}` failed with {status}"));
+            }
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        let sanitizer = |name: &str| self.sanitizers.iter().any(|sanitizer| sanitizer == name);
+        if sanitizer("address") && sanitizer("thread") {
+            return Err(anyhow::anyhow!(
+                "the `address` and `thread` sanitizers cannot be combined in `{}`",
+                self.output.display()
+            ));
+        }
+        if let Some(triple) = &self.zig_target {
+            if self.tool_chain != ToolChain::Zig {
+                tracing::warn!("zig_target `{triple}` is ignored by {:?}", self.tool_chain);
+            } else if triple.split('-').filter(|part| !part.is_empty()).count() != 3 {
+                return Err(anyhow::anyhow!("zig_target `{triple}` is not an `arch-os-abi` triple"));
+            }
+        }
+        if let Some(level) = self.debug_info && level > 3 {
+            return Err(anyhow::anyhow!("debug_info must be between 0 and 3, got {level}"));
+        }
+        if self.lto == Lto::Thin && self.tool_chain != ToolChain::Clang {
+            tracing::warn!("thin LTO is only supported by clang; using full LTO with {:?}", self.tool_chain);
+        }
+        if self.strip && !self.should_strip() {
+            tracing::warn!("not stripping `{}` since it is built with debug info", self.output().display());
+        }
+        if self.pie && self.typ != BinaryType::Executable {
+            tracing::warn!("pie is ignored for {:?} `{}`", self.typ, self.output.display());
+        }
+        if !(self.frameworks.is_empty() && self.framework_paths.is_empty()) && self.target_os() != Os::MacOs {
+            tracing::warn!("frameworks are only supported on macOS; ignoring {:?}", self.frameworks);
+        }
+        if self.tool_chain == ToolChain::Msvc {
+            self.args.warnings.iter().for_each(|warning| {
+                if let WarningFlag::ErrorOn(name) = warning && name.parse::<u32>().is_err() {
+                    tracing::warn!("msvc needs a warning number for `ErrorOn`, ignoring `{name}`");
+                }
+            });
+        }
+        if self.tool_chain == ToolChain::Msvc && self.sysroot.is_some() {
+            tracing::warn!("sysroot is ignored by msvc");
+        }
+        if !self.args.custom.is_empty() {
+            tracing::warn!("`custom` is deprecated and applies to both compiles and links; use `compile_flags` or `link_flags`");
+        }
+        if let Some(object) = self.objects.iter().find(|object| !object.is_file()) {
+            return Err(anyhow::anyhow!("prebuilt object not found: {}", object.display()));
+        }
+        if self.unity_batch_size == Some(0) {
+            return Err(anyhow::anyhow!("unity_batch_size must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// Rebases every relative path the script gives, from sources and include directories
+    /// to the cache directory and install prefix, onto `base`, the directory of the build script.
+    pub fn resolve_paths(&mut self, base: &Path) {
+        if base.as_os_str().is_empty() {
+            return;
+        }
+        let rebase = |path: &mut PathBuf| {
+            if path.is_relative() {
+                *path = base.join(&*path);
+            }
+        };
+        let rebase_file = |path: &mut PathBuf| match Self::list_file(path) {
+            Some(list) if list.is_relative() => {
+                let mut resolved = std::ffi::OsString::from("@");
+                resolved.push(base.join(list));
+                *path = PathBuf::from(resolved);
+            }
+            Some(_) => {}
+            None => rebase(path),
+        };
+        self.files.iter_mut().for_each(rebase_file);
+        self.platform_files.iter_mut().for_each(rebase_file);
+        self.includes.iter_mut().for_each(rebase);
+        self.system_includes.iter_mut().for_each(rebase);
+        self.objects.iter_mut().for_each(rebase);
+        self.excludes.iter_mut().flatten().for_each(rebase);
+        self.sysroot.iter_mut().for_each(rebase);
+        self.framework_paths.iter_mut().for_each(rebase);
+        self.install_headers.iter_mut().for_each(rebase);
+        self.install_prefix.iter_mut().for_each(rebase);
+        self.cache_dir.iter_mut().for_each(rebase);
+        if let Some(BinDir::Path(path)) = &mut self.bin_dir {
+            rebase(path);
+        }
+        if let Some(MapFile::Path(path)) = &mut self.map_file {
+            rebase(path);
+        }
+        self.lib_paths.iter_mut().filter(|path| Path::new(path).is_relative()).for_each(|path| {
+            *path = base.join(&*path).to_string_lossy().into_owned();
+        });
+        rebase(&mut self.src_dir);
+        rebase(&mut self.output);
+        self.overrides = std::mem::take(&mut self.overrides)
+            .into_iter()
+            .map(|(pattern, flags)| (base.join(pattern).to_string_lossy().into_owned(), flags))
+            .collect();
+    }
+
+    /// Fails early, naming every program this graph needs for its sources that is not on `PATH`.
+    async fn validate_tools(&self) -> Result<()> {
+        let languages = self
+            .collect_sources()
+            .await?
+            .iter()
+            .map(|file| match Language::from_path(file) {
+                Language::Asm => Language::Asm,
+                guessed => self.language.unwrap_or(guessed),
+            })
+            .collect::<Vec<_>>();
+        let mut tools = Vec::new();
+        if languages.contains(&Language::C) {
+            tools.push(self.tool_chain.compiler());
+        }
+        if languages.contains(&Language::Cpp) {
+            tools.push(self.tool_chain.cxx_compiler());
+        }
+        if languages.contains(&Language::Asm) {
+            tools.push(self.tool_chain.assembler(self.is_64bit()));
+        }
+        tools.push(match self.typ {
+            BinaryType::StaticLib => self.archiver(),
+            _ if languages.contains(&Language::Cpp) => self.tool_chain.cxx_linker(&self.typ),
+            _ => self.tool_chain.linker(&self.typ),
+        });
+        if let Some(launcher) = &self.compiler_launcher {
+            tools.push(launcher);
+        }
+        let mut missing = tools
+            .into_iter()
+            .filter(|tool| !crate::find_program(tool))
+            .map(String::from)
+            .collect::<Vec<_>>();
+        missing.sort();
+        missing.dedup();
+        let mut errors = missing.into_iter().map(|tool| BuildError::ToolchainNotFound { tool }).collect::<Vec<_>>();
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0).into()),
+            _ => Err(BuildError::Multiple(errors).into()),
+        }
+    }
+
+    fn is_64bit(&self) -> bool {
+        self.target.as_ref().map_or(cfg!(target_pointer_width = "64"), Target::is_64bit)
+    }
+
+    /// Returns a copy of this graph with the flags of every `pkg_config` package merged in.
+    async fn with_pkg_config(&self) -> Result<Graph> {
+        let mut graph = self.clone();
+        graph.pkg_config.clear();
+        if self.tool_chain == ToolChain::Msvc {
+            tracing::warn!("pkg-config is not supported with msvc; ignoring {:?}", self.pkg_config);
+            return Ok(graph);
+        }
+        for package in &self.pkg_config {
+            for flag in Self::pkg_config_query(package, "--cflags").await? {
+                if let Some(include) = flag.strip_prefix("-I") {
+                    graph.includes.push(PathBuf::from(include));
+                } else if let Some(define) = flag.strip_prefix("-D") {
+                    graph.args.defines.push(define.to_string());
+                } else {
+                    graph.args.compile_flags.push(flag);
+                }
+            }
+            for flag in Self::pkg_config_query(package, "--libs").await? {
+                if let Some(path) = flag.strip_prefix("-L") {
+                    graph.lib_paths.push(path.to_string());
+                } else if let Some(lib) = flag.strip_prefix("-l") {
+                    graph.libs.push(lib.to_string());
+                } else {
+                    graph.args.link_flags.push(flag);
+                }
+            }
+        }
+        Ok(graph)
+    }
+
+    async fn pkg_config_query(package: &str, query: &str) -> Result<Vec<String>> {
+        let out = Command::new("pkg-config")
+            .args([query, package])
+            .output()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to run pkg-config for `{package}`: {e}"))?;
+        if !out.status.success() {
+            return Err(anyhow::anyhow!(
+                "pkg-config package `{package}` not found: {}",
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout)
+            .split_whitespace()
+            .map(str::to_string)
+            .collect())
+    }
+
+    fn debug_info_level(&self) -> Option<u8> {
+        match (self.debug_info, &self.opt_level) {
+            (Some(level), _) => Some(level),
+            (None, OptimizationLevel::Debug | OptimizationLevel::O0) => Some(2),
+            (None, _) => None,
+        }
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.typ == BinaryType::Executable
+    }
+
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| {
+            self.output.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+        })
+    }
+
+    pub fn binary_type(&self) -> &BinaryType {
+        &self.typ
+    }
+
+    pub fn tool_chain(&self) -> &ToolChain {
+        &self.tool_chain
+    }
+
+    /// Links against the output of `dependency`. Executables are only ordered
+    /// before this graph, there is nothing to link.
+    pub fn add_dependency(&mut self, dependency: &Graph) {
+        if dependency.typ == BinaryType::Executable {
+            return;
+        }
+        let output = dependency.output();
+        // `-l` can't find a library without its `lib` prefix, so it is linked by path
+        if dependency.no_lib_prefix && dependency.target_os() != Os::Window {
+            self.objects.push(output);
+            return;
+        }
+        let dir = output
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let dir = dir.display().to_string();
+        if !self.lib_paths.contains(&dir) {
+            self.lib_paths.push(dir);
+        }
+        if let Some(name) = dependency.output_name().file_name() {
+            self.libs.push(name.to_string_lossy().into_owned());
+        }
+    }
+
+    /// Copies the built output, plus `install_headers` for libraries, below
+    /// `install_prefix` and returns the installed path. Without a prefix the
+    /// output is copied out of `bin_dir` to `output`, or left where it was built.
+    pub async fn install(&self, built: &Path) -> Result<PathBuf> {
+        if self.dry_run {
+            return Ok(built.to_path_buf());
+        }
+        let Some(prefix) = &self.install_prefix else {
+            if self.bin_dir().is_none() {
+                return Ok(built.to_path_buf());
+            }
+            let installed = self.user_output();
+            if let Some(parent) = installed.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::create_dir_all(parent).await?;
+            }
+            tracing::info!("[Installing]: {} -> {}", built.display(), installed.display());
+            fs::copy(built, &installed).await?;
+            return Ok(installed);
+        };
+        let dir = match self.typ {
+            BinaryType::Executable => "bin",
+            BinaryType::DynLib if self.target_os() == Os::Window => "bin",
+            BinaryType::DynLib | BinaryType::StaticLib => "lib",
+        };
+        let dir = prefix.join(dir);
+        fs::create_dir_all(&dir).await?;
+        let file_name = built
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("`{}` has no file name", built.display()))?;
+        let installed = dir.join(file_name);
+        tracing::info!("[Installing]: {} -> {}", built.display(), installed.display());
+        // `fs::copy` carries the permission bits over, so executables stay executable
+        fs::copy(built, &installed).await?;
+
+        if self.typ != BinaryType::Executable {
+            let include_dir = prefix.join("include");
+            for header in &self.install_headers {
+                let (root, headers) = if header.is_dir() {
+                    (header.as_path(), Self::read_dir(header).await?)
+                } else {
+                    (header.parent().unwrap_or(Path::new("")), vec![header.clone()])
+                };
+                for header in headers {
+                    let dest = include_dir.join(header.strip_prefix(root).unwrap_or(&header));
+                    if let Some(parent) = dest.parent() {
+                        fs::create_dir_all(parent).await?;
+                    }
+                    tracing::info!("[Installing]: {} -> {}", header.display(), dest.display());
+                    fs::copy(&header, &dest).await?;
+                }
+            }
+        }
+        Ok(installed)
+    }
+
+    /// Removes the object and thin LTO caches, or the whole cache directory unless `objects_only` is set.
+    pub async fn clean_cache(cache_dir: &Path, objects_only: bool) -> Result<()> {
+        let dirs = if objects_only {
+            let mut dirs = Vec::new();
+            if let Ok(mut profiles) = read_dir(cache_dir).await {
+                while let Some(profile) = profiles.next_entry().await? {
+                    dirs.push(profile.path().join(Self::OBJ_DIR));
+                    dirs.push(profile.path().join(Self::SHARED_OBJ_DIR));
+                }
+                dirs.push(cache_dir.join(Self::LTO_CACHE_DIR));
+            }
+            dirs
+        } else {
+            vec![cache_dir.to_path_buf()]
+        };
+        for dir in dirs {
+            match fs::remove_dir_all(&dir).await {
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+                Ok(()) => tracing::info!("[Cleaning]: {}", dir.display()),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn cache_dir(&self) -> PathBuf {
+        self.cache_dir.clone().unwrap_or_else(|| PathBuf::from(Self::CACHE_DIR))
+    }
+
+    /// Objects are kept per optimization level so profiles never share them, and per output so
+    /// binaries compiling the same source with different flags never overwrite each other.
+    fn obj_dir(&self) -> PathBuf {
+        self.cache_dir()
+            .join(self.opt_level.profile())
+            .join(Self::OBJ_DIR)
+            .join(self.output.file_name().unwrap_or_default())
+    }
+
+    /// Objects shared through the build's object cache belong to no single output, so they
+    /// stay put whichever graph happens to compile them first.
+    fn shared_obj_dir(&self) -> PathBuf {
+        self.cache_dir().join(self.opt_level.profile()).join(Self::SHARED_OBJ_DIR)
+    }
+
+    /// Removes the final output of this graph.
+    pub async fn clean(&self) -> Result<()> {
+        let output = self.output();
+        tracing::info!("[Cleaning]: {}", output.display());
+        match fs::remove_file(&output).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Directories whose contents affect this graph's objects.
+    pub fn watch_paths(&self) -> Vec<PathBuf> {
+        std::iter::once(self.src_dir.clone())
+            .chain(self.includes.iter().cloned())
+            .chain(self.system_includes.iter().cloned())
+            .collect()
+    }
+
+    /// Builds the `compile_commands.json` entries for every source of this graph.
+    pub async fn compile_commands(&self) -> Result<Vec<CompileCommand>> {
+        if !self.pkg_config.is_empty() {
+            return Box::pin(self.with_pkg_config().await?.compile_commands()).await;
+        }
+        let directory = std::env::current_dir()?;
+        self.input_files()
+            .await?
+            .iter()
+            .map(|file| file.compile_command(&directory))
+            .collect()
+    }
+
+    /// The compile unit of `file` with all of this graph's flags, if the graph
+    /// compiles it.
+    pub async fn find_input_file(&self, file: &Path) -> Result<Option<InputFile>> {
+        if !self.pkg_config.is_empty() {
+            return Box::pin(self.with_pkg_config().await?.find_input_file(file)).await;
+        }
+        let file = std::path::absolute(file)?;
+        Ok(self
+            .input_files()
+            .await?
+            .into_iter()
+            .find(|input| std::path::absolute(input.path()).is_ok_and(|path| path == file)))
+    }
+
+    /// Every source this graph compiles: `files` with directories and globs
+    /// expanded and `excludes` applied.
+    pub async fn collect_sources(&self) -> Result<Vec<PathBuf>> {
+        let mut input_files = Vec::with_capacity(self.files.len());
+
+        let files = self.expand_list_files().await?;
+        let files = files.iter().filter(|file| !self.is_excluded(file));
+
+        let mut missing = Vec::new();
+        for file in files {
+            if file.is_dir() {
+                input_files.extend(Self::read_dir(file).await?)
+            } else if file.exists() {
+                input_files.push(file.clone());
+            } else if Self::is_glob(file) {
+                let matches = expand_glob(&file.to_string_lossy())?;
+                if matches.is_empty() {
+                    tracing::warn!("`{}` did not match any files", file.display());
+                }
+                input_files.extend(matches);
+            } else {
+                missing.push(file.clone());
+            }
+        }
+        if let Some((path, rest)) = missing.split_first() {
+            rest.iter().for_each(|path| tracing::error!("source file not found: {}", path.display()));
+            return Err(BuildError::SourceMissing { path: path.clone() }.into());
+        }
+        input_files.retain(|file| !self.is_excluded(file));
+        Ok(input_files)
+    }
+
+    /// Every source of this graph paired with the object it compiles to.
+    pub async fn source_objects(&self) -> Result<Vec<(PathBuf, PathBuf)>> {
+        Ok(self
+            .input_files()
+            .await?
+            .into_iter()
+            .map(|file| (file.path().to_path_buf(), file.output_path))
+            .collect())
+    }
+
+    async fn input_files(&self) -> Result<Vec<InputFile>> {
+        let input_files = self.collect_sources().await?;
+        let options = Arc::new(CompileOptions {
+            tool_chain: self.tool_chain.clone(),
+            opt_level: self.opt_level.clone(),
+            args: self.args.clone(),
+            includes: self.includes.clone(),
+            system_includes: self.system_includes.clone(),
+            std: self.std.clone(),
+            language: self.language,
+            is_64bit: self.is_64bit(),
+            target_flags: self.target_flags(),
+            lto: self.lto(),
+            gc_sections: self.gc_sections,
+            sanitizers: self.sanitizers.clone(),
+            debug_info: self.debug_info_level(),
+            position_independent: self.pic || self.typ == BinaryType::DynLib,
+            pie: self.pie && self.typ == BinaryType::Executable,
+            env: self.tool_env(),
+            timings: self.timings.clone(),
+            reporter: self.reporter.clone(),
+            compiler_launcher: self
+                .compiler_launcher
+                .clone()
+                .or_else(|| std::env::var("CARGOC_COMPILER_LAUNCHER").ok()),
+            full_rebuild: self.full_rebuild,
+            mtime_only: self.mtime_only,
+            explain: self.explain,
+            color: self.color,
+            json_depfiles: self.json_depfiles,
+            dry_run: self.dry_run,
+            timeout: self.timeout,
+            sysroot: self.sysroot().map(Path::to_path_buf),
+        });
+        let input_files = input_files
+            .into_iter()
+            .map(|file| {
+                let output = self.object_path(&file);
                 (file, output)
             })
             .map(|(input, output)| {
-                InputFile::new(input, output, self.tool_chain.clone(), self.args.clone(), self.includes.clone(), self.full_rebuild)
+                let options = self.options_for(&input, &options);
+                InputFile::new(input, output, options)
+            })
+            .map(|file| match self.object_cache {
+                Some(_) => file.shared_in(&self.shared_obj_dir()),
+                None => file,
             })
             .collect::<Vec<_>>();
-        for file in &input_files {
-            if let Some(dir) = file.output_path.parent() && let Ok(exists) = fs::try_exists(dir).await && !exists {
-                fs::create_dir_all(dir).await?;
+        Ok(input_files)
+    }
+
+    /// Object path mirroring `file` below `src_dir`. Sources outside of it are
+    /// flattened into `external/` with a hash of their path, so `..` or absolute
+    /// components can never escape the object directory or collide.
+    fn object_path(&self, file: &Path) -> PathBuf {
+        let relative = file
+            .strip_prefix(&self.src_dir)
+            .ok()
+            .filter(|relative| relative.components().all(|component| matches!(component, Component::Normal(_))));
+        let ext = self.obj_file_ext();
+        match relative {
+            Some(relative) => self.with_obj_ext(&self.obj_dir().join(relative)),
+            None => {
+                let absolute = std::path::absolute(file).unwrap_or_else(|_| file.to_path_buf());
+                let hash = blake3::hash(absolute.as_os_str().as_encoded_bytes()).to_hex();
+                let stem = file.file_stem().map(|stem| stem.to_string_lossy()).unwrap_or_default();
+                self.obj_dir().join("external").join(format!("{stem}-{}.{ext}", &hash[..16]))
             }
         }
-        let mut set = JoinSet::new();
-        input_files.into_iter().for_each(|file| {
-            set.spawn(async move { file.compile().await });
-        });
-        let output_files = set
-            .join_all()
-            .await
-            .into_iter()
-            .collect::<Result<Vec<_>>>()?;
+    }
 
-        let program = self.link(&output_files).await?;
+    /// Appends the object extension instead of replacing the source's, so
+    /// `util.c` and `util.cpp` in one directory get distinct objects.
+    fn with_obj_ext(&self, path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".");
+        name.push(self.obj_file_ext());
+        PathBuf::from(name)
+    }
 
-        Ok(program)
+    /// The list file named by a `files` entry of the form `@sources.txt`.
+    fn list_file(path: &Path) -> Option<&Path> {
+        path.to_str()?.strip_prefix('@').map(Path::new)
+    }
+
+    /// `files` plus the `platform_files` of the target OS, with every `@list` entry
+    /// replaced by the paths listed in it, one per line and relative to the list
+    /// file. Blank lines and `#` comments are skipped.
+    async fn expand_list_files(&self) -> Result<Vec<PathBuf>> {
+        let target_os = self.target_os();
+        let platform_files = self.platform_files.for_os(&target_os);
+        let mut files = Vec::with_capacity(self.files.len() + platform_files.len());
+        for file in self.files.iter().chain(platform_files) {
+            let Some(list) = Self::list_file(file) else {
+                files.push(file.clone());
+                continue;
+            };
+            let contents = fs::read_to_string(list)
+                .await
+                .map_err(|e| anyhow::anyhow!("failed to read source list `{}`: {e}", list.display()))?;
+            let dir = list.parent().unwrap_or(Path::new(""));
+            files.extend(
+                contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(|line| dir.join(line)),
+            );
+        }
+        Ok(files)
+    }
+
+    fn is_glob(path: &Path) -> bool {
+        path.to_string_lossy().contains(['*', '?', '[', '{'])
+    }
+
+    /// Whether `file` is listed in, lives below, or matches a glob in `excludes`.
+    /// Paths are compared without `.` components and, when both exist, canonicalized,
+    /// so `./src/foo.c`, `src/foo.c` and an absolute spelling all match each other.
+    fn is_excluded(&self, file: &Path) -> bool {
+        let Some(excludes) = &self.excludes else {
+            return false;
+        };
+        let normalized = Self::normalize(file);
+        let canonical = file.canonicalize().ok();
+        excludes.iter().any(|exclude| {
+            if Self::is_glob(exclude) {
+                let pattern = Self::normalize(exclude);
+                return glob::Pattern::new(&pattern.to_string_lossy()).is_ok_and(|glob| glob.matches_path(&normalized));
+            }
+            normalized.starts_with(Self::normalize(exclude))
+                || canonical
+                    .as_ref()
+                    .zip(exclude.canonicalize().ok())
+                    .is_some_and(|(file, exclude)| file.starts_with(exclude))
+        })
+    }
+
+    /// `path` without `.` components.
+    fn normalize(path: &Path) -> PathBuf {
+        path.components().filter(|component| *component != Component::CurDir).collect()
+    }
+
+    /// Replaces every batch of `batch_size` C or C++ sources with a generated
+    /// translation unit `#include`-ing them. Assembly and sources with
+    /// overrides keep being compiled on their own.
+    async fn unity_files(&self, files: Vec<InputFile>, batch_size: usize) -> Result<Vec<InputFile>> {
+        let mut input_files = Vec::new();
+        let mut groups: BTreeMap<&str, Vec<InputFile>> = BTreeMap::new();
+        for file in files {
+            let language = match Language::from_path(file.path()) {
+                Language::Asm => Language::Asm,
+                guessed => self.language.unwrap_or(guessed),
+            };
+            match language {
+                _ if self.has_override(file.path()) => input_files.push(file),
+                Language::Asm => input_files.push(file),
+                Language::C => groups.entry("c").or_default().push(file),
+                Language::Cpp => groups.entry("cpp").or_default().push(file),
+            }
+        }
+
+        let dir = self.obj_dir().join("unity");
+        if !self.dry_run {
+            crate::create_dir_all(&dir).await?;
+        }
+        let name = self.output.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        for (ext, files) in groups {
+            for (index, batch) in files.chunks(batch_size).enumerate() {
+                let mut source = String::new();
+                for file in batch {
+                    source.push_str(&format!("#include \"{}\"\n", std::path::absolute(file.path())?.display()));
+                }
+                let path = dir.join(format!("{name}_unity_{index}.{ext}"));
+                // rewriting an unchanged unity file would force a recompile in mtime mode
+                if !self.dry_run && fs::read_to_string(&path).await.ok().as_deref() != Some(source.as_str()) {
+                    fs::write(&path, &source).await?;
+                }
+                let output = self.with_obj_ext(&path);
+                input_files.push(InputFile::new(path, output, batch[0].options().clone()));
+            }
+        }
+        Ok(input_files)
+    }
+
+    fn has_override(&self, file: &Path) -> bool {
+        self.overrides.keys().any(|pattern| Self::override_matches(pattern, file))
+    }
+
+    fn override_matches(pattern: &str, file: &Path) -> bool {
+        file.starts_with(pattern) || glob::Pattern::new(pattern).is_ok_and(|glob| glob.matches_path(file))
+    }
+
+    fn options_for(&self, file: &Path, options: &Arc<CompileOptions>) -> Arc<CompileOptions> {
+        let mut overrides = self
+            .overrides
+            .iter()
+            .filter(|(pattern, _)| Self::override_matches(pattern, file))
+            .peekable();
+        if overrides.peek().is_none() {
+            return options.clone();
+        }
+        let mut options = CompileOptions::clone(options);
+        overrides.for_each(|(_, flags)| options.args.merge(flags));
+        Arc::new(options)
     }
 
-    async fn link(&self, files: &[OutputFile]) -> Result<PathBuf> {
-        if !self.should_recompile(files)? {
-            tracing::info!("{} is up to date", self.output().display());
+    async fn link(&self, files: &[OutputFile]) -> Result<PathBuf, BuildError> {
+        let cmd = match self.typ {
+            BinaryType::StaticLib => self.archive_command(files),
+            _ => self.link_command(files),
+        };
+        if self.dry_run {
+            println!("{}", cmd.display());
             return Ok(self.output());
         }
+        let fingerprint = format!("{:?} strip={}\n{}", self.typ, self.strip, cmd.display());
+        match self.should_recompile(files, &fingerprint)? {
+            Decision::UpToDate => {
+                tracing::info!("{} is up to date", self.output().display());
+                return Ok(self.output());
+            }
+            Decision::Rebuild(reason) if self.explain => {
+                tracing::info!("relinking `{}`: {reason}", self.output().display());
+            }
+            Decision::Rebuild(_) => {}
+        }
+
+        // outputs like `build/bin/app`, or any inside `bin_dir`, need their directory first
+        if let Some(dir) = self.output().parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            crate::create_dir_all(dir).await?;
+        }
+        let output = match self.typ {
+            BinaryType::StaticLib => self.archive(cmd).await?,
+            _ => self.run_linker(cmd).await?,
+        };
+        fs::write(self.link_fingerprint_path(), fingerprint).await?;
+        Ok(output)
+    }
 
-        let mut cmd = Command::new(self.tool_chain.linker(&self.typ));
+    fn link_command(&self, files: &[OutputFile]) -> Command {
+        let language = if files.iter().any(|file| file.language == Language::Cpp) {
+            Language::Cpp
+        } else {
+            Language::C
+        };
+        let linker = match language {
+            Language::C | Language::Asm => self.tool_chain.linker(&self.typ),
+            Language::Cpp => self.tool_chain.cxx_linker(&self.typ),
+        };
+        let mut cmd = Command::new(linker);
+        cmd.envs(self.tool_env());
         if self.tool_chain == ToolChain::Zig {
-            cmd.arg("cc");
+            cmd.arg(language.zig_command());
         }
+        cmd.args(self.target_flags());
 
         self.append_out(&mut cmd);
+        if self.typ == BinaryType::DynLib {
+            cmd.args(self.tool_chain.shared_lib_flags());
+        }
         self.append_files(&mut cmd, files);
         self.append_args(&mut cmd);
+        if self.linker_args_position == LinkerArgsPosition::BeforeLibs {
+            cmd.args(&self.linker_args);
+        }
         self.append_libs(&mut cmd);
+        if self.linker_args_position == LinkerArgsPosition::AfterLibs {
+            cmd.args(&self.linker_args);
+        }
+        self.append_rpaths(&mut cmd);
+        cmd
+    }
 
+    async fn run_linker(&self, cmd: Command) -> Result<PathBuf> {
         tracing::info!("[Linking]: {}", self.output().display());
         tracing::debug!("[Linking]: Command = {}", cmd.display());
-        let out = cmd.spawn()?.wait().await;
+        let keep = usize::from(self.tool_chain == ToolChain::Zig);
+        let mut cmd = response_file::apply(cmd, keep, &self.response_file_path(), &self.tool_chain).await?;
+        let _permit = self.acquire_job().await?;
+        let start = Instant::now();
+        let out = self.run_with_retries(&mut cmd, "linker").await;
+        self.record_link(start, matches!(&out, Ok(status) if status.success()));
         match out {
             Ok(out) if !out.success() => {
-                return Err(anyhow::anyhow!("failed to link `{}`; compilation aborted", self.output.display()));
+                return Err(BuildError::LinkFailed { output: self.output() }.into());
             }
             Err(e) => {
-                return Err(anyhow::anyhow!("failed to link `{}`; compilation aborted: {}", self.output.display(), e));
+                return Err(anyhow::anyhow!("failed to link `{}`; compilation aborted: {:#}", self.output.display(), e));
             }
             _ => {},
         }
 
+        if self.strips_after_link() {
+            self.strip().await?;
+        }
+
         Ok(self.output())
     }
 
-    fn append_out(&self, cmd: &mut Command) {
-        let output = self.output().display().to_string();
-        if self.tool_chain == ToolChain::Msvc {
-            cmd.arg(format!("/OUT:{}", output));
-            return;
+    /// Runs `cmd`, retrying up to `link_retries` times with a growing delay when
+    /// it can't be started or waited on because a file is locked, as antivirus
+    /// scanners do on Windows. A nonzero exit is never retried.
+    async fn run_with_retries(&self, cmd: &mut Command, tool: &str) -> Result<std::process::ExitStatus> {
+        // an interrupted build must not leave the linker behind
+        cmd.kill_on_drop(true);
+        let mut attempt = 0;
+        loop {
+            let out = match crate::spawn(cmd, tool) {
+                Ok(mut child) => self.wait(&mut child, tool).await,
+                Err(e) => Err(e),
+            };
+            match out {
+                Err(e) if attempt < self.link_retries && Self::is_transient(&e) => {
+                    attempt += 1;
+                    tracing::warn!("{tool} failed ({e:#}), retrying {attempt}/{}", self.link_retries);
+                    tokio::time::sleep(Duration::from_millis(100 << attempt)).await;
+                }
+                out => return out,
+            }
         }
-        cmd.args([self.tool_chain.linker_output_flag(), output.as_str()]);
-    }
-
-    fn append_files(&self, cmd: &mut Command, files: &[OutputFile]) {
-        cmd.args(files.iter().map(|file| &file.path));
     }
 
-    fn append_args(&self, cmd: &mut Command) {
-        if self.tool_chain == ToolChain::Msvc {
-            cmd.arg("/nologo");
+    /// Waits for `child`, killing it once it outlives `timeout`.
+    async fn wait(&self, child: &mut Child, tool: &str) -> Result<std::process::ExitStatus> {
+        let Some(timeout) = self.timeout else {
+            return Ok(child.wait().await?);
+        };
+        match tokio::time::timeout(timeout, child.wait()).await {
+            Ok(status) => Ok(status?),
+            Err(_) => {
+                child.kill().await?;
+                let what = format!("{tool} for `{}`", self.output().display());
+                Err(BuildError::Timeout { what, secs: timeout.as_secs() }.into())
+            }
         }
-        cmd.args(&self.args.custom);
     }
 
-    fn append_libs(&self, cmd: &mut Command) {
-        self.libs.iter().for_each(|path| {
-            cmd.arg(format!("{}{}", self.tool_chain.linker_link_lib(), path));
-        });
-        self.lib_paths.iter().for_each(|path| {
-            cmd.arg(format!("{}{}", self.tool_chain.linker_link_dir_flag(), path));
-        });
+    /// Sharing and lock violations, and the access denied errors they surface as.
+    fn is_transient(error: &anyhow::Error) -> bool {
+        error.downcast_ref::<std::io::Error>().is_some_and(|e| {
+            e.kind() == std::io::ErrorKind::PermissionDenied || (cfg!(windows) && matches!(e.raw_os_error(), Some(32 | 33)))
+        })
     }
 
-    fn should_recompile(&self, files: &[OutputFile]) -> Result<bool> {
-        if self.full_rebuild {
-            return Ok(true);
+    async fn acquire_job(&self) -> Result<Option<OwnedSemaphorePermit>> {
+        match &self.jobs {
+            Some(jobs) => Ok(Some(jobs.clone().acquire_owned().await?)),
+            None => Ok(None),
         }
-        let Ok(output_metadata) = self.output().metadata() else {
-            return Ok(true);
-        };
+    }
 
-        for file in files {
-            let metadata = file.path.metadata()?;
-            if metadata.modified()? > output_metadata.modified()? {
-                return Ok(true);
-            }
+    fn record_link(&self, start: Instant, success: bool) {
+        if let Some(timings) = &self.timings {
+            timings.record(Step::Link, self.output(), start.elapsed());
+        }
+        if let Some(reporter) = &self.reporter {
+            reporter.report(&Message::LinkFinished {
+                output: &self.output(),
+                success,
+                seconds: start.elapsed().as_secs_f64(),
+            });
         }
-        Ok(false)
     }
 
-    fn output(&self) -> PathBuf {
-        if cfg!(target_os = "windows") {
-            let ext = match self.typ {
-                BinaryType::Executable => "exe",
-                BinaryType::DynLib => "dll",
-                BinaryType::StaticLib => "lib",
-            };
-            self.output.with_extension(ext)
-        }else {
-            self.output.clone()
-        }
+    fn should_strip(&self) -> bool {
+        // debug builds carry debug info by default, which stripping would throw away
+        self.strip && self.debug_info_level().is_none_or(|level| level == 0)
     }
 
-    fn read_dir(path: impl AsRef<Path>) -> impl Future<Output = Result<Vec<PathBuf>>> {
+    /// Apple's linker ignores `-s`, so their binaries are stripped by `strip` once linked.
+    fn strips_after_link(&self) -> bool {
+        self.should_strip() && self.target_os() == Os::MacOs && !matches!(self.tool_chain, ToolChain::Msvc | ToolChain::Zig)
+    }
+
+    async fn strip(&self) -> Result<()> {
+        let mut cmd = Command::new("strip");
+        cmd.envs(self.tool_env());
+        cmd.kill_on_drop(true);
+        cmd.arg(self.output());
+
+        tracing::info!("[Stripping]: {}", self.output().display());
+        tracing::debug!("[Stripping]: Command = {}", cmd.display());
+        let out = cmd.spawn()?.wait().await;
+        match out {
+            Ok(out) if !out.success() => {
+                Err(anyhow::anyhow!("failed to strip `{}`", self.output.display()))
+            }
+            Err(e) => {
+                Err(anyhow::anyhow!("failed to strip `{}`: {}", self.output.display(), e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// `env` plus a valid `SOURCE_DATE_EPOCH` from cargoc's environment, passed on
+    /// explicitly so gcc and clang pin `__DATE__` and `__TIME__` to it. `env` wins.
+    fn tool_env(&self) -> BTreeMap<String, String> {
+        let mut env = self.env.clone();
+        if let Some(epoch) = crate::source_date_epoch() {
+            env.entry("SOURCE_DATE_EPOCH".to_string()).or_insert(epoch);
+        }
+        env
+    }
+
+    fn archiver(&self) -> &str {
+        // gcc's LTO objects need the plugin aware `gcc-ar` to get a symbol index
+        match (&self.tool_chain, self.lto()) {
+            (ToolChain::Gcc, Lto::Full) => "gcc-ar",
+            (chain, _) => chain.archiver(),
+        }
+    }
+
+    fn archive_command(&self, files: &[OutputFile]) -> Command {
+        let mut cmd = Command::new(self.archiver());
+        cmd.envs(self.tool_env());
+        if self.tool_chain == ToolChain::Msvc {
+            cmd.arg("/nologo");
+            if self.lto() != Lto::Off {
+                cmd.arg("/LTCG");
+            }
+            self.append_out(&mut cmd);
+        } else {
+            cmd.arg("rcs").arg(self.output());
+        }
+        self.append_files(&mut cmd, files);
+        cmd
+    }
+
+    async fn archive(&self, cmd: Command) -> Result<PathBuf> {
+        if self.tool_chain != ToolChain::Msvc {
+            // `ar r` only replaces members, so stale objects would otherwise linger in the archive
+            _ = fs::remove_file(self.output()).await;
+        }
+        tracing::info!("[Archiving]: {}", self.output().display());
+        tracing::debug!("[Archiving]: Command = {}", cmd.display());
+        let mut cmd = response_file::apply(cmd, 0, &self.response_file_path(), &self.tool_chain).await?;
+        let _permit = self.acquire_job().await?;
+        let start = Instant::now();
+        let out = self.run_with_retries(&mut cmd, "archiver").await;
+        self.record_link(start, matches!(&out, Ok(status) if status.success()));
+        match out {
+            Ok(out) if !out.success() => {
+                return Err(BuildError::LinkFailed { output: self.output() }.into());
+            }
+            Err(e) => {
+                return Err(anyhow::anyhow!("failed to archive `{}`; compilation aborted: {:#}", self.output.display(), e));
+            }
+            _ => {},
+        }
+
+        Ok(self.output())
+    }
+
+    /// The graph's own sysroot, otherwise the one of a `Custom` toolchain.
+    fn sysroot(&self) -> Option<&Path> {
+        self.sysroot.as_deref().or_else(|| self.tool_chain.sysroot())
+    }
+
+    /// Shared by every profile, the cache is keyed by the modules' contents.
+    fn lto_cache_dir(&self) -> PathBuf {
+        self.cache_dir().join(Self::LTO_CACHE_DIR)
+    }
+
+    /// Response file for the link step, named after the output so graphs
+    /// sharing a cache never clash.
+    fn response_file_path(&self) -> PathBuf {
+        let name = self.output().file_name().map(|name| name.to_os_string()).unwrap_or_default();
+        let mut path = self.obj_dir().join(name);
+        path.as_mut_os_string().push(".rsp");
+        path
+    }
+
+    /// The link command and options of the last successful link, compared to
+    /// decide whether a relink is needed even though no object changed.
+    fn link_fingerprint_path(&self) -> PathBuf {
+        let mut path = self.response_file_path();
+        path.set_extension("link");
+        path
+    }
+
+    fn append_out(&self, cmd: &mut Command) {
+        if self.tool_chain == ToolChain::Msvc {
+            cmd.arg(crate::path_arg("/OUT:", self.output()));
+            return;
+        }
+        cmd.arg(self.tool_chain.linker_output_flag()).arg(self.output());
+    }
+
+    fn append_files(&self, cmd: &mut Command, files: &[OutputFile]) {
+        cmd.args(files.iter().map(|file| &file.path));
+    }
+
+    fn append_args(&self, cmd: &mut Command) {
+        if self.tool_chain == ToolChain::Msvc {
+            cmd.arg("/nologo");
+            if self.should_strip() {
+                cmd.args(["/OPT:REF", "/OPT:ICF"]);
+            } else if self.debug_info_level().is_some_and(|level| level > 0) {
+                cmd.arg("/DEBUG");
+            }
+        } else if let Some(flag) = self.tool_chain.sanitize_flag(&self.sanitizers) {
+            cmd.arg(flag);
+        }
+        if self.tool_chain != ToolChain::Msvc && self.should_strip() && !self.strips_after_link() {
+            cmd.arg("-s");
+        }
+        if self.gc_sections {
+            match (&self.tool_chain, self.target_os()) {
+                // stripping already removes unreferenced sections
+                (ToolChain::Msvc, _) if self.should_strip() => {}
+                (ToolChain::Msvc, _) => {
+                    cmd.arg("/OPT:REF");
+                }
+                (_, Os::MacOs) => {
+                    cmd.arg("-Wl,-dead_strip");
+                }
+                _ => {
+                    cmd.arg("-Wl,--gc-sections");
+                }
+            }
+        }
+        if let Some(flag) = self.tool_chain.lto_link_flag(&self.lto()) {
+            cmd.arg(flag);
+        }
+        if self.tool_chain != ToolChain::Msvc && let Some(sysroot) = self.sysroot() {
+            cmd.arg(crate::path_arg("--sysroot=", sysroot));
+        }
+        if self.lto() == Lto::Thin {
+            // thin LTO reuses the optimized modules of unchanged objects from this cache
+            let flag = match self.target_os() {
+                Os::MacOs => "-Wl,-cache_path_lto,",
+                _ => "-Wl,--thinlto-cache-dir=",
+            };
+            cmd.arg(crate::path_arg(flag, self.lto_cache_dir()));
+        }
+        if self.pie && self.typ == BinaryType::Executable && self.tool_chain != ToolChain::Msvc {
+            cmd.arg("-pie");
+        }
+        if let Some(map_file) = self.map_file() {
+            let flag = match (&self.tool_chain, self.target_os()) {
+                (ToolChain::Msvc, _) => "/MAP:",
+                (_, Os::MacOs) => "-Wl,-map,",
+                _ => "-Wl,-Map=",
+            };
+            cmd.arg(crate::path_arg(flag, map_file));
+        }
+        cmd.args(self.args.link_args());
+    }
+
+    fn append_libs(&self, cmd: &mut Command) {
+        self.libs.iter().for_each(|lib| {
+            cmd.arg(self.tool_chain.link_lib_arg(lib));
+        });
+        self.append_whole_archive_libs(cmd);
+        self.lib_paths.iter().for_each(|path| {
+            cmd.arg(format!("{}{}", self.tool_chain.linker_link_dir_flag(), path));
+        });
+        if self.target_os() == Os::MacOs {
+            self.framework_paths.iter().for_each(|path| {
+                cmd.arg(crate::path_arg("-F", path));
+            });
+            self.frameworks.iter().for_each(|framework| {
+                cmd.arg("-framework").arg(framework);
+            });
+        }
+    }
+
+    fn append_whole_archive_libs(&self, cmd: &mut Command) {
+        if self.whole_archive_libs.is_empty() {
+            return;
+        }
+        if self.tool_chain == ToolChain::Msvc {
+            self.whole_archive_libs.iter().for_each(|lib| {
+                cmd.arg(format!("/WHOLEARCHIVE:{}", self.tool_chain.link_lib_arg(lib)));
+            });
+        } else if self.target_os() == Os::MacOs {
+            // `-force_load` takes the archive itself rather than a library name
+            self.whole_archive_libs.iter().for_each(|lib| {
+                cmd.arg(crate::path_arg("-Wl,-force_load,", self.find_archive(lib)));
+            });
+        } else {
+            cmd.arg("-Wl,--whole-archive");
+            self.whole_archive_libs.iter().for_each(|lib| {
+                cmd.arg(self.tool_chain.link_lib_arg(lib));
+            });
+            cmd.arg("-Wl,--no-whole-archive");
+        }
+    }
+
+    /// Resolves a library name to its `lib<name>.a` in `lib_paths`; paths are
+    /// taken as they are.
+    fn find_archive(&self, lib: &str) -> PathBuf {
+        let path = Path::new(lib);
+        if path.extension().is_some_and(|ext| ext == "a") || path.components().count() > 1 {
+            return path.to_path_buf();
+        }
+        let archive = format!("lib{lib}.a");
+        self.lib_paths
+            .iter()
+            .map(|dir| Path::new(dir).join(&archive))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| PathBuf::from(archive))
+    }
+
+    /// Resolves a `libs` entry to the file the linker would pick from `lib_paths`,
+    /// if any; libraries found elsewhere, like system ones, are not resolved.
+    fn find_library(&self, lib: &str) -> Option<PathBuf> {
+        let names = match (&self.tool_chain, self.target_os()) {
+            (ToolChain::Msvc, _) | (_, Os::Window) => vec![format!("{lib}.lib"), format!("lib{lib}.a")],
+            (_, Os::MacOs) => vec![format!("lib{lib}.dylib"), format!("lib{lib}.a")],
+            _ => vec![format!("lib{lib}.so"), format!("lib{lib}.a")],
+        };
+        self.lib_paths.iter().find_map(|dir| {
+            names.iter().map(|name| Path::new(dir).join(name)).find(|path| path.is_file())
+        })
+    }
+
+    /// Every library file this graph links that could be found.
+    fn linked_libraries(&self) -> Vec<PathBuf> {
+        let mut libraries = self.libs.iter().filter_map(|lib| self.find_library(lib)).collect::<Vec<_>>();
+        libraries.extend(
+            self.whole_archive_libs
+                .iter()
+                .map(|lib| self.find_archive(lib))
+                .filter(|path| path.is_file()),
+        );
+        libraries
+    }
+
+    fn append_rpaths(&self, cmd: &mut Command) {
+        if self.tool_chain == ToolChain::Msvc || self.target_os() == Os::Window {
+            return;
+        }
+        self.rpaths.iter().for_each(|path| {
+            // `$ORIGIN` is spelled `@loader_path` by the macOS linker
+            let path = match (self.target_os(), path.strip_prefix("$ORIGIN")) {
+                (Os::MacOs, Some(rest)) => format!("@loader_path{rest}"),
+                _ => path.clone(),
+            };
+            cmd.arg(format!("-Wl,-rpath,{path}"));
+        });
+    }
+
+    fn should_recompile(&self, files: &[OutputFile], fingerprint: &str) -> Result<Decision> {
+        if self.full_rebuild {
+            return Ok(Decision::Rebuild(Reason::FullRebuild));
+        }
+        let Ok(output_metadata) = self.output().metadata() else {
+            return Ok(Decision::Rebuild(Reason::Missing(self.output())));
+        };
+        match std::fs::read_to_string(self.link_fingerprint_path()) {
+            Ok(previous) if previous == fingerprint => {}
+            Ok(_) => return Ok(Decision::Rebuild(Reason::FlagsChanged)),
+            Err(_) => return Ok(Decision::Rebuild(Reason::NoRecord)),
+        }
+
+        for file in files {
+            let metadata = file.path.metadata()?;
+            if metadata.modified()? > output_metadata.modified()? {
+                return Ok(Decision::Rebuild(Reason::Newer(file.path.clone())));
+            }
+        }
+        // static libraries are copied into the output, so a rebuilt one needs a relink
+        for library in self.linked_libraries() {
+            if library.metadata()?.modified()? > output_metadata.modified()? {
+                return Ok(Decision::Rebuild(Reason::Newer(library)));
+            }
+        }
+        Ok(Decision::UpToDate)
+    }
+
+    fn map_file(&self) -> Option<PathBuf> {
+        match self.map_file.as_ref()? {
+            MapFile::Enabled(true) => Some(self.output().with_extension("map")),
+            MapFile::Enabled(false) => None,
+            MapFile::Path(path) => Some(path.clone()),
+        }
+    }
+
+    /// The requested LTO mode, with `Thin` falling back to `Full` off clang.
+    fn lto(&self) -> Lto {
+        match self.lto {
+            Lto::Thin if self.tool_chain != ToolChain::Clang => Lto::Full,
+            lto => lto,
+        }
+    }
+
+    fn target_flags(&self) -> Vec<String> {
+        match (&self.tool_chain, &self.zig_target, &self.target) {
+            (ToolChain::Zig, Some(triple), _) => vec!["-target".to_string(), triple.clone()],
+            (_, _, Some(target)) => self.tool_chain.target_flags(target),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Zig names objects after its target's convention, `.obj` for msvc-abi Windows,
+    /// while still taking gcc-style flags since `zig cc` is clang-compatible.
+    fn obj_file_ext(&self) -> &str {
+        match (&self.tool_chain, &self.zig_target) {
+            (ToolChain::Zig, Some(triple)) if triple.ends_with("-windows-msvc") => "obj",
+            (tool_chain, _) => tool_chain.obj_file_ext(),
+        }
+    }
+
+    fn target_os(&self) -> Os {
+        if self.tool_chain == ToolChain::Zig && let Some(triple) = &self.zig_target {
+            return match triple.split('-').nth(1) {
+                Some("windows") => Os::Window,
+                Some("linux") => Os::Linux,
+                Some("macos") => Os::MacOs,
+                _ => Os::UnixLike,
+            };
+        }
+        self.target.as_ref().map(Target::os).unwrap_or_else(Os::current)
+    }
+
+    /// Where the binary is linked: inside `bin_dir` when set, `user_output` otherwise.
+    pub fn output(&self) -> PathBuf {
+        let output = self.user_output();
+        match (self.bin_dir(), output.file_name()) {
+            (Some(dir), Some(name)) => dir.join(name),
+            _ => output,
+        }
+    }
+
+    fn bin_dir(&self) -> Option<PathBuf> {
+        match self.bin_dir.as_ref()? {
+            BinDir::Enabled(true) => Some(self.cache_dir().join(Self::BIN_DIR)),
+            BinDir::Enabled(false) => None,
+            BinDir::Path(path) => Some(path.clone()),
+        }
+    }
+
+    /// `output` with the platform's prefix and extension applied.
+    fn user_output(&self) -> PathBuf {
+        let os = self.target_os();
+        if os == Os::Window {
+            let ext = match self.typ {
+                BinaryType::Executable => "exe",
+                BinaryType::DynLib => "dll",
+                BinaryType::StaticLib => "lib",
+            };
+            self.output_name().with_extension(ext)
+        }else {
+            match self.typ {
+                BinaryType::Executable => self.output_name(),
+                BinaryType::DynLib if os == Os::MacOs => self.lib_output().with_extension("dylib"),
+                BinaryType::DynLib => self.lib_output().with_extension("so"),
+                BinaryType::StaticLib => self.lib_output().with_extension("a"),
+            }
+        }
+    }
+
+    /// `output`, suffixed with the profile when `output_suffix_by_profile` is set.
+    fn output_name(&self) -> PathBuf {
+        if !self.output_suffix_by_profile {
+            return self.output.clone();
+        }
+        let name = self.output.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        self.output.with_file_name(format!("{name}-{}", self.opt_level.profile()))
+    }
+
+    /// `output_name` with the `lib` prefix `-l` looks for, unless `no_lib_prefix` is set.
+    fn lib_output(&self) -> PathBuf {
+        let output = self.output_name();
+        if self.no_lib_prefix {
+            return output;
+        }
+        let name = output.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+        output.with_file_name(format!("lib{name}"))
+    }
+
+    fn read_dir(path: impl AsRef<Path>) -> impl Future<Output = Result<Vec<PathBuf>>> {
         Box::pin(async move {
             let mut files = Vec::new();
             let mut read_dir = read_dir(path).await?;
@@ -402,3 +2309,1245 @@ impl Graph {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(json: serde_json::Value) -> Graph {
+        serde_json::from_value(json).unwrap()
+    }
+
+    /// A gcc debug graph without sources, with `json` laid over it.
+    fn minimal(json: serde_json::Value) -> Graph {
+        let mut base = serde_json::json!({ "tool_chain": "Gcc", "opt_level": "Debug", "files": [] });
+        base.as_object_mut().unwrap().extend(json.as_object().unwrap().clone());
+        graph(base)
+    }
+
+    /// A graph compiling `sources`, written to `dir` as empty files, into `dir/app`.
+    fn graph_in(dir: &Path, sources: &[&str], mut json: serde_json::Value) -> Graph {
+        let files = sources
+            .iter()
+            .map(|source| {
+                let path = dir.join(source);
+                std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+                std::fs::write(&path, "").unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        let defaults = serde_json::json!({
+            "tool_chain": "Gcc",
+            "opt_level": "Debug",
+            "files": files,
+            "src_dir": dir,
+            "output": dir.join("app"),
+            "cache_dir": dir.join(".cargoc"),
+        });
+        for (key, value) in defaults.as_object().unwrap() {
+            json.as_object_mut().unwrap().entry(key).or_insert(value.clone());
+        }
+        graph(json)
+    }
+
+    fn objects(paths: &[&str]) -> Vec<OutputFile> {
+        paths.iter().map(|path| OutputFile { path: PathBuf::from(path), language: Language::C }).collect()
+    }
+
+    /// The link command for `objects`, program first.
+    fn link_args(graph: &Graph, paths: &[&str]) -> Vec<String> {
+        graph.link_command(&objects(paths)).arguments()
+    }
+
+    #[tokio::test]
+    async fn compile_database_has_one_entry_per_source() {
+        let dir = crate::scratch_dir("compile_database");
+        let graph = graph_in(&dir, &["main.c", "my util.c"], serde_json::json!({}));
+        let json = serde_json::to_string(&graph.compile_commands().await.unwrap()).unwrap();
+        let commands: Vec<CompileCommand> = serde_json::from_str(&json).unwrap();
+        assert_eq!(commands.len(), 2);
+        for (command, source) in commands.iter().zip(["main.c", "my util.c"]) {
+            assert_eq!(command.file, dir.join(source));
+            assert!(command.directory.is_absolute());
+            assert_eq!(command.arguments[0], "gcc");
+            // a path with a space stays one argument
+            assert!(command.arguments.contains(&dir.join(source).display().to_string()));
+        }
+    }
+
+    #[test]
+    fn dynamic_libraries_link_as_shared_objects() {
+        let lib = graph(serde_json::json!({
+            "tool_chain": "Gcc", "opt_level": "Debug", "files": [], "type": "DynLib",
+            "output": "out/foo", "target": "LinuxX64",
+        }));
+        assert_eq!(lib.output(), Path::new("out/libfoo.so"));
+        let args = link_args(&lib, &["a.o"]);
+        assert_eq!(args[..5], ["gcc", "-m64", "-o", "out/libfoo.so", "-shared"]);
+        assert!(args.contains(&"-fPIC".to_string()));
+
+        let lib = graph(serde_json::json!({
+            "tool_chain": "Msvc", "opt_level": "Debug", "files": [], "type": "DynLib",
+            "output": "out/foo", "target": "WindowsX64",
+        }));
+        assert_eq!(lib.output(), Path::new("out/foo.dll"));
+        assert_eq!(link_args(&lib, &["a.obj"])[..3], ["link.exe", "/OUT:out/foo.dll", "/DLL"]);
+    }
+
+    #[test]
+    fn static_libraries_are_archived() {
+        let lib = graph(serde_json::json!({
+            "tool_chain": "Gcc", "opt_level": "Debug", "files": [], "type": "StaticLib",
+            "output": "out/foo", "target": "LinuxX64",
+        }));
+        let args = lib.archive_command(&objects(&["a.o", "b.o"])).arguments();
+        assert_eq!(args, ["ar", "rcs", "out/libfoo.a", "a.o", "b.o"]);
+
+        let lib = graph(serde_json::json!({
+            "tool_chain": "Msvc", "opt_level": "Debug", "files": [], "type": "StaticLib",
+            "output": "out/foo", "target": "WindowsX64",
+        }));
+        let args = lib.archive_command(&objects(&["a.obj"])).arguments();
+        assert_eq!(args, ["lib.exe", "/nologo", "/OUT:out/foo.lib", "a.obj"]);
+    }
+
+    #[tokio::test]
+    async fn jobs_bound_concurrent_processes() {
+        let mut graph = graph(serde_json::json!({ "tool_chain": "Gcc", "opt_level": "Debug", "files": [] }));
+        graph.jobs = Some(Arc::new(Semaphore::new(1)));
+        let permit = graph.acquire_job().await.unwrap();
+        assert!(permit.is_some());
+        assert!(tokio::time::timeout(Duration::from_millis(50), graph.acquire_job()).await.is_err());
+        drop(permit);
+        assert!(graph.acquire_job().await.unwrap().is_some());
+    }
+
+    #[test]
+    fn targets_select_architecture_and_platform() {
+        assert_eq!(ToolChain::Gcc.target_flags(&Target::LinuxX86), ["-m32"]);
+        assert_eq!(ToolChain::Clang.target_flags(&Target::LinuxX86), ["--target=i686-unknown-linux-gnu", "-m32"]);
+        assert_eq!(ToolChain::Zig.target_flags(&Target::WindowsX64), ["-target", "x86_64-windows-gnu"]);
+        assert!(ToolChain::Msvc.target_flags(&Target::WindowX86).is_empty());
+
+        let exe = graph(serde_json::json!({
+            "tool_chain": "Clang", "opt_level": "Debug", "files": [], "output": "app", "target": "WindowsX64",
+        }));
+        assert_eq!(exe.output(), Path::new("app.exe"));
+        assert_eq!(link_args(&exe, &["a.o"])[..5], ["clang", "--target=x86_64-pc-windows-gnu", "-m64", "-o", "app.exe"]);
+    }
+
+    #[tokio::test]
+    async fn clean_removes_objects_or_the_whole_cache() {
+        let dir = crate::scratch_dir("clean");
+        let cache = dir.join(".cargoc");
+        for path in ["debug/obj/app/main.c.o", "debug/shared/util.c-0.o", "lto/module", "bin/app"] {
+            std::fs::create_dir_all(cache.join(path).parent().unwrap()).unwrap();
+            std::fs::write(cache.join(path), "").unwrap();
+        }
+        Graph::clean_cache(&cache, true).await.unwrap();
+        assert!(!cache.join("debug/obj").exists());
+        assert!(!cache.join("debug/shared").exists());
+        assert!(!cache.join("lto").exists());
+        assert!(cache.join("bin/app").exists());
+
+        Graph::clean_cache(&cache, false).await.unwrap();
+        assert!(!cache.exists());
+        // cleaning twice is not an error
+        Graph::clean_cache(&cache, false).await.unwrap();
+
+        let graph = graph_in(&dir, &[], serde_json::json!({}));
+        std::fs::write(graph.output(), "").unwrap();
+        graph.clean().await.unwrap();
+        assert!(!graph.output().exists());
+    }
+
+    #[tokio::test]
+    async fn missing_sources_fail_before_compiling() {
+        let dir = crate::scratch_dir("missing_sources");
+        let graph = graph_in(&dir, &["main.c"], serde_json::json!({
+            "files": [dir.join("main.c"), dir.join("gone.c")],
+        }));
+        let error = BuildError::from(graph.collect_sources().await.unwrap_err());
+        assert!(matches!(&error, BuildError::SourceMissing { path } if *path == dir.join("gone.c")), "{error:?}");
+    }
+
+    #[tokio::test]
+    async fn overrides_apply_to_matching_sources_only() {
+        let dir = crate::scratch_dir("overrides");
+        let vendor = dir.join("vendor").display().to_string();
+        let legacy = dir.join("**/legacy.c").display().to_string();
+        let graph = graph_in(&dir, &["main.c", "vendor/lib.c", "old/legacy.c"], serde_json::json!({
+            "args": { "defines": ["BASE"] },
+            "overrides": {
+                vendor: { "defines": ["VENDOR"] },
+                legacy: { "compile_flags": ["-w"] },
+            },
+        }));
+        let commands = graph.compile_commands().await.unwrap();
+        let args = |source: &str| commands.iter().find(|command| command.file == dir.join(source)).unwrap().arguments.clone();
+        let has = |source: &str, arg: &str| args(source).iter().any(|candidate| candidate == arg);
+        assert!(has("main.c", "-DBASE") && !has("main.c", "-DVENDOR") && !has("main.c", "-w"));
+        assert!(has("vendor/lib.c", "-DBASE") && has("vendor/lib.c", "-DVENDOR"));
+        assert!(has("old/legacy.c", "-w") && !has("old/legacy.c", "-DVENDOR"));
+    }
+
+    #[test]
+    fn sanitizers_reach_compiles_and_links() {
+        let sanitizers = vec!["address".to_string(), "undefined".to_string()];
+        assert_eq!(ToolChain::Clang.sanitize_flag(&sanitizers).as_deref(), Some("-fsanitize=address,undefined"));
+        assert_eq!(ToolChain::Msvc.sanitize_flag(&sanitizers).as_deref(), Some("/fsanitize=address"));
+        assert_eq!(ToolChain::Gcc.sanitize_flag(&[]), None);
+
+        let app = graph(serde_json::json!({
+            "tool_chain": "Gcc", "opt_level": "Debug", "files": [], "sanitizers": ["address", "undefined"],
+        }));
+        assert!(link_args(&app, &["a.o"]).contains(&"-fsanitize=address,undefined".to_string()));
+
+        let both = graph(serde_json::json!({
+            "tool_chain": "Gcc", "opt_level": "Debug", "files": [], "sanitizers": ["address", "thread"],
+        }));
+        assert!(both.validate().is_err());
+    }
+
+    #[test]
+    fn debug_info_defaults_to_the_profile() {
+        assert_eq!(minimal(serde_json::json!({})).debug_info_level(), Some(2));
+        assert_eq!(minimal(serde_json::json!({ "opt_level": "Release" })).debug_info_level(), None);
+        assert_eq!(minimal(serde_json::json!({ "opt_level": "Release", "debug_info": 3 })).debug_info_level(), Some(3));
+        assert!(minimal(serde_json::json!({ "debug_info": 4 })).validate().is_err());
+
+        assert_eq!(ToolChain::Gcc.debug_info_flag(0).as_deref(), Some("-g0"));
+        assert_eq!(ToolChain::Msvc.debug_info_flag(1).as_deref(), Some("/Zi"));
+        assert_eq!(ToolChain::Msvc.debug_info_flag(0), None);
+        let msvc = minimal(serde_json::json!({ "tool_chain": "Msvc" }));
+        assert!(link_args(&msvc, &["a.obj"]).contains(&"/DEBUG".to_string()));
+    }
+
+    #[test]
+    fn strip_skips_binaries_with_debug_info() {
+        assert!(minimal(serde_json::json!({ "opt_level": "Release", "strip": true })).should_strip());
+        assert!(!minimal(serde_json::json!({ "opt_level": "Release" })).should_strip());
+        // debug builds carry debug info unless told otherwise
+        assert!(!minimal(serde_json::json!({ "strip": true })).should_strip());
+        assert!(minimal(serde_json::json!({ "strip": true, "debug_info": 0 })).should_strip());
+        assert!(!minimal(serde_json::json!({ "opt_level": "Release", "strip": true, "debug_info": 1 })).should_strip());
+
+        let msvc = minimal(serde_json::json!({ "tool_chain": "Msvc", "opt_level": "Release", "strip": true }));
+        let args = link_args(&msvc, &["a.obj"]);
+        assert!(args.contains(&"/OPT:REF".to_string()) && !args.contains(&"/DEBUG".to_string()));
+        // zig strips Apple targets itself
+        let zig = minimal(serde_json::json!({ "tool_chain": "Zig", "zig_target": "aarch64-macos-none", "opt_level": "Release", "strip": true }));
+        assert!(link_args(&zig, &["a.o"]).contains(&"-s".to_string()) && !zig.strips_after_link());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn stripped_links_drop_the_symbol_table() {
+        let dir = crate::scratch_dir("strip");
+        let has_symbols = |path: &Path| std::fs::read(path).unwrap().windows(7).any(|window| window == b".symtab");
+        let mut plain = graph_in(&dir, &["main.c"], serde_json::json!({ "opt_level": "Release" }));
+        let mut stripped = graph_in(&dir, &["main.c"], serde_json::json!({ "opt_level": "Release", "strip": true, "output": dir.join("stripped") }));
+        std::fs::write(dir.join("main.c"), "int main(void) { return 0; }\n").unwrap();
+        plain.quiet = true;
+        stripped.quiet = true;
+        assert!(has_symbols(&plain.build().await.unwrap()));
+        assert!(link_args(&stripped, &["main.o"]).contains(&"-s".to_string()));
+        assert!(!has_symbols(&stripped.build().await.unwrap()));
+    }
+
+    #[test]
+    fn rpaths_follow_the_target_linker() {
+        let rpaths = serde_json::json!(["$ORIGIN/../lib"]);
+        let linux = minimal(serde_json::json!({ "rpaths": rpaths, "target": "LinuxX64" }));
+        assert!(link_args(&linux, &["a.o"]).contains(&"-Wl,-rpath,$ORIGIN/../lib".to_string()));
+
+        let macos = minimal(serde_json::json!({ "rpaths": rpaths, "tool_chain": "Zig", "zig_target": "aarch64-macos" }));
+        assert!(link_args(&macos, &["a.o"]).contains(&"-Wl,-rpath,@loader_path/../lib".to_string()));
+
+        let windows = minimal(serde_json::json!({ "rpaths": rpaths, "target": "WindowsX64" }));
+        assert!(!link_args(&windows, &["a.o"]).iter().any(|arg| arg.contains("rpath")));
+    }
+
+    #[tokio::test]
+    async fn pkg_config_flags_are_merged_into_the_graph() {
+        let dir = crate::scratch_dir("pkg_config");
+        let package = dir.join("foo.pc");
+        std::fs::write(
+            &package,
+            "Name: foo\nDescription: test\nVersion: 1\nCflags: -I/opt/foo/include -DFOO=1 -pthread\nLibs: -L/opt/foo/lib -lfoo -pthread\n",
+        )
+        .unwrap();
+        let package = package.to_string_lossy().into_owned();
+        let graph = minimal(serde_json::json!({ "pkg_config": [package] })).with_pkg_config().await.unwrap();
+        assert!(graph.pkg_config.is_empty());
+        assert_eq!(graph.includes, vec![PathBuf::from("/opt/foo/include")]);
+        assert_eq!(graph.args.defines, vec!["FOO=1".to_string()]);
+        assert_eq!(graph.args.compile_flags, vec!["-pthread".to_string()]);
+        assert_eq!(graph.lib_paths, vec!["/opt/foo/lib".to_string()]);
+        assert_eq!(graph.libs, vec!["foo".to_string()]);
+        assert_eq!(graph.args.link_flags, vec!["-pthread".to_string()]);
+
+        let msvc = minimal(serde_json::json!({ "tool_chain": "Msvc", "pkg_config": [package] }));
+        assert!(msvc.with_pkg_config().await.unwrap().libs.is_empty());
+
+        let missing = minimal(serde_json::json!({ "pkg_config": ["cbuild-no-such-package"] }));
+        assert!(missing.with_pkg_config().await.unwrap_err().to_string().contains("`cbuild-no-such-package` not found"));
+    }
+
+    #[tokio::test]
+    async fn env_reaches_every_child_process() {
+        let dir = crate::scratch_dir("env");
+        let graph = graph_in(&dir, &["main.c"], serde_json::json!({ "env": { "CBUILD_TEST": "1" } }));
+        let has_env = |cmd: &Command| {
+            cmd.as_std().get_envs().any(|(key, value)| key == "CBUILD_TEST" && value == Some("1".as_ref()))
+        };
+        let inputs = graph.input_files().await.unwrap();
+        assert_eq!(inputs[0].options().env.get("CBUILD_TEST").map(String::as_str), Some("1"));
+        assert!(has_env(&graph.link_command(&objects(&["main.o"]))));
+        assert!(has_env(&graph.archive_command(&objects(&["main.o"]))));
+    }
+
+    #[tokio::test]
+    async fn objects_live_under_the_cache_dir_per_profile() {
+        let dir = crate::scratch_dir("cache_dir");
+        let debug = graph_in(&dir, &["main.c"], serde_json::json!({ "cache_dir": dir.join("cache") }));
+        assert_eq!(debug.cache_dir(), dir.join("cache"));
+        let object = debug.input_files().await.unwrap()[0].output_path.clone();
+        assert!(object.starts_with(dir.join("cache/debug/obj/app")), "{}", object.display());
+
+        let release = graph_in(&dir, &["main.c"], serde_json::json!({ "cache_dir": dir.join("cache"), "opt_level": "Release" }));
+        assert!(release.input_files().await.unwrap()[0].output_path.starts_with(dir.join("cache/release/obj/app")));
+
+        assert_eq!(minimal(serde_json::json!({})).cache_dir(), PathBuf::from(Graph::CACHE_DIR));
+    }
+
+    #[tokio::test]
+    async fn globs_select_and_exclude_sources() {
+        let dir = crate::scratch_dir("globs");
+        let graph = graph_in(&dir, &["src/main.c", "src/util.c", "src/gen_table.c", "src/notes.txt"], serde_json::json!({
+            "files": [dir.join("src/*.c")],
+            "excludes": [dir.join("src/gen_*.c")],
+        }));
+        let mut sources = graph.input_files().await.unwrap().iter().map(|file| file.path().to_path_buf()).collect::<Vec<_>>();
+        sources.sort();
+        assert_eq!(sources, vec![dir.join("src/main.c"), dir.join("src/util.c")]);
+    }
+
+    #[tokio::test]
+    async fn timings_record_every_compile_and_the_link() {
+        let dir = crate::scratch_dir("timings");
+        let mut graph = graph_in(&dir, &["main.c", "util.c"], serde_json::json!({}));
+        std::fs::write(dir.join("main.c"), "int main(void) { return 0; }\n").unwrap();
+        let timings = Arc::new(Timings::default());
+        graph.timings = Some(timings.clone());
+        graph.quiet = true;
+        graph.build().await.unwrap();
+
+        let entries = timings.entries();
+        let compiles = entries.iter().filter(|timing| timing.step == Step::Compile).count();
+        assert_eq!(compiles, 2);
+        assert!(entries.iter().any(|timing| timing.step == Step::Link && timing.path == dir.join("app")));
+    }
+
+    #[tokio::test]
+    async fn zig_target_reaches_compiles_and_links() {
+        let dir = crate::scratch_dir("zig_target");
+        let zig = graph_in(&dir, &["main.c"], serde_json::json!({
+            "tool_chain": "Zig", "target": "LinuxX64", "zig_target": "aarch64-linux-musl",
+        }));
+        let target = vec!["-target".to_string(), "aarch64-linux-musl".to_string()];
+        assert_eq!(zig.input_files().await.unwrap()[0].options().target_flags, target);
+        let args = link_args(&zig, &["main.o"]);
+        assert_eq!(&args[..4], ["zig", "cc", "-target", "aarch64-linux-musl"]);
+
+        assert!(minimal(serde_json::json!({ "tool_chain": "Zig", "zig_target": "aarch64-linux" })).validate().is_err());
+        let gcc = minimal(serde_json::json!({ "zig_target": "aarch64-linux-musl" }));
+        assert!(gcc.validate().is_ok() && gcc.target_flags().is_empty());
+    }
+
+    #[tokio::test]
+    async fn install_copies_outputs_and_headers_under_the_prefix() {
+        let dir = crate::scratch_dir("install");
+        std::fs::create_dir_all(dir.join("include/foo")).unwrap();
+        std::fs::write(dir.join("include/foo/foo.h"), "").unwrap();
+        std::fs::write(dir.join("libfoo.a"), "archive").unwrap();
+        let lib = graph_in(&dir, &[], serde_json::json!({
+            "type": "StaticLib",
+            "install_prefix": dir.join("prefix"),
+            "install_headers": [dir.join("include")],
+        }));
+        let installed = lib.install(&dir.join("libfoo.a")).await.unwrap();
+        assert_eq!(installed, dir.join("prefix/lib/libfoo.a"));
+        assert_eq!(std::fs::read_to_string(&installed).unwrap(), "archive");
+        assert!(dir.join("prefix/include/foo/foo.h").is_file());
+
+        std::fs::write(dir.join("app"), "").unwrap();
+        let app = graph_in(&dir, &[], serde_json::json!({ "install_prefix": dir.join("prefix") }));
+        assert_eq!(app.install(&dir.join("app")).await.unwrap(), dir.join("prefix/bin/app"));
+    }
+
+    #[test]
+    fn dependencies_are_linked_by_name_from_their_dir() {
+        let mut app = minimal(serde_json::json!({ "target": "LinuxX64" }));
+        app.add_dependency(&minimal(serde_json::json!({ "type": "StaticLib", "output": "out/foo", "target": "LinuxX64" })));
+        app.add_dependency(&minimal(serde_json::json!({ "type": "DynLib", "output": "out/bar", "target": "LinuxX64" })));
+        app.add_dependency(&minimal(serde_json::json!({ "output": "out/tool", "target": "LinuxX64" })));
+        assert_eq!(app.lib_paths, vec!["out".to_string()]);
+        assert_eq!(app.libs, vec!["foo".to_string(), "bar".to_string()]);
+
+        let args = link_args(&app, &["main.o"]);
+        let position = |arg: &str| args.iter().position(|candidate| candidate == arg).unwrap();
+        // archives only resolve symbols referenced by the objects before them
+        assert!(position("main.o") < position("-lfoo") && position("-lfoo") < position("-lbar"));
+        assert!(args.contains(&"-Lout".to_string()));
+    }
+
+    #[test]
+    fn cpp_objects_link_with_the_cpp_driver() {
+        let app = minimal(serde_json::json!({}));
+        let mut files = objects(&["main.o"]);
+        assert_eq!(app.link_command(&files).arguments()[0], "gcc");
+        files.push(OutputFile { path: PathBuf::from("util.o"), language: Language::Cpp });
+        assert_eq!(app.link_command(&files).arguments()[0], "g++");
+
+        let lib = minimal(serde_json::json!({ "type": "StaticLib" }));
+        assert_eq!(ToolChain::Gcc.cxx_linker(&lib.typ), ToolChain::Gcc.linker(&lib.typ));
+    }
+
+    #[tokio::test]
+    async fn unity_batches_include_sources_per_language() {
+        let dir = crate::scratch_dir("unity");
+        let graph = graph_in(&dir, &["a.c", "b.c", "c.c", "d.cpp", "start.S"], serde_json::json!({}));
+        let inputs = graph.unity_files(graph.input_files().await.unwrap(), 2).await.unwrap();
+        let mut sources = inputs.iter().map(|file| file.path().to_path_buf()).collect::<Vec<_>>();
+        sources.sort();
+        let unity = dir.join(".cargoc/debug/obj/app/unity");
+        assert_eq!(
+            sources,
+            vec![unity.join("app_unity_0.c"), unity.join("app_unity_0.cpp"), unity.join("app_unity_1.c"), dir.join("start.S")]
+        );
+        let first = std::fs::read_to_string(unity.join("app_unity_0.c")).unwrap();
+        assert_eq!(first, format!("#include \"{}\"\n#include \"{}\"\n", dir.join("a.c").display(), dir.join("b.c").display()));
+    }
+
+    #[tokio::test]
+    async fn lto_flags_reach_compiles_and_links() {
+        let dir = crate::scratch_dir("lto");
+        // only clang knows thin lto
+        let gcc = graph_in(&dir, &["main.c"], serde_json::json!({ "lto": "Thin" }));
+        assert_eq!(gcc.input_files().await.unwrap()[0].options().lto, Lto::Full);
+        assert!(link_args(&gcc, &["main.o"]).contains(&"-flto".to_string()));
+
+        let clang = graph_in(&dir, &["main.c"], serde_json::json!({ "lto": "Thin", "tool_chain": "Clang" }));
+        assert_eq!(clang.input_files().await.unwrap()[0].options().lto, Lto::Thin);
+        assert!(link_args(&clang, &["main.o"]).contains(&"-flto=thin".to_string()));
+
+        assert_eq!(ToolChain::Msvc.lto_compile_flag(&Lto::Full), Some("/GL"));
+        let msvc = minimal(serde_json::json!({ "lto": "Thin", "tool_chain": "Msvc" }));
+        assert!(link_args(&msvc, &["main.obj"]).contains(&"/LTCG".to_string()));
+        assert!(!link_args(&minimal(serde_json::json!({ "lto": "Off" })), &["main.o"]).iter().any(|arg| arg.starts_with("-flto")));
+    }
+
+    #[tokio::test]
+    async fn pie_only_applies_to_executables() {
+        let dir = crate::scratch_dir("pie");
+        let app = graph_in(&dir, &["main.c"], serde_json::json!({ "pie": true }));
+        assert!(app.input_files().await.unwrap()[0].options().pie);
+        assert!(link_args(&app, &["main.o"]).contains(&"-pie".to_string()));
+
+        let lib = graph_in(&dir, &["main.c"], serde_json::json!({ "pie": true, "pic": true, "type": "StaticLib" }));
+        let options = lib.input_files().await.unwrap()[0].options().clone();
+        assert!(options.position_independent && !options.pie);
+    }
+
+    #[tokio::test]
+    async fn versions_are_read_from_the_banner() {
+        assert_eq!(ToolChain::parse_version("gcc (GCC) 13.2.0").as_deref(), Some("13.2.0"));
+        assert_eq!(ToolChain::parse_version("Ubuntu clang version 17.0.6 (1ubuntu1)").as_deref(), Some("17.0.6"));
+        assert_eq!(ToolChain::parse_version("0.13.0").as_deref(), Some("0.13.0"));
+        assert_eq!(
+            ToolChain::parse_version("Microsoft (R) C/C++ Optimizing Compiler Version 19.38.33130 for x64").as_deref(),
+            Some("19.38.33130")
+        );
+        assert_eq!(ToolChain::parse_version("no digits here"), None);
+
+        let gcc = ToolChain::Gcc.version().await.unwrap();
+        assert!(gcc.starts_with(|c: char| c.is_ascii_digit()), "{gcc}");
+        let missing: ToolChain = serde_json::from_value(serde_json::json!({ "compiler": "cbuild-no-such-cc", "linker": "ld" })).unwrap();
+        assert_eq!(missing.version().await, None);
+    }
+
+    #[test]
+    fn objects_never_escape_the_object_dir() {
+        let graph = minimal(serde_json::json!({ "src_dir": "/project/src", "output": "app" }));
+        let obj_dir = graph.obj_dir();
+        assert_eq!(graph.object_path(Path::new("/project/src/net/http.c")), obj_dir.join("net/http.c.o"));
+
+        let outside = [Path::new("/project/vendor/http.c"), Path::new("/project/src/../vendor/http.c"), Path::new("/other/http.c")];
+        let objects = outside.map(|file| graph.object_path(file));
+        for object in &objects {
+            assert_eq!(object.parent(), Some(obj_dir.join("external").as_path()), "{}", object.display());
+        }
+        assert_ne!(objects[0], objects[2]);
+    }
+
+    #[tokio::test]
+    async fn keep_going_reports_every_failed_compile() {
+        let dir = crate::scratch_dir("keep_going");
+        let mut graph = graph_in(&dir, &["bad.c", "good.c", "worse.c"], serde_json::json!({}));
+        std::fs::write(dir.join("bad.c"), "int main(void) { return }\n").unwrap();
+        std::fs::write(dir.join("worse.c"), "this is not c\n").unwrap();
+        graph.quiet = true;
+        graph.reporter = Some(Arc::new(crate::Recorder::default()));
+
+        let failed = |error: &BuildError| match error {
+            BuildError::CompileFailed { file, .. } => file.file_name().unwrap().to_string_lossy().into_owned(),
+            error => panic!("unexpected {error}"),
+        };
+        let first = graph.build().await.unwrap_err();
+        assert!(["bad.c", "worse.c"].contains(&failed(&first).as_str()));
+
+        graph.keep_going = true;
+        let BuildError::Multiple(errors) = graph.build().await.unwrap_err() else {
+            panic!("expected every failure");
+        };
+        let mut files = errors.iter().map(failed).collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["bad.c", "worse.c"]);
+        assert!(graph.input_files().await.unwrap().iter().any(|file| file.path().ends_with("good.c") && file.output_path.is_file()));
+    }
+
+    #[test]
+    fn custom_toolchains_override_gcc_conventions() {
+        let tool_chain = serde_json::json!({
+            "compiler": "tcc", "linker": "tcc", "obj_file_ext": "obj", "output_flag": "-o=",
+            "link_lib_flag": "--lib=", "link_dir_flag": "--libdir=", "archiver": "tar",
+        });
+        let app = minimal(serde_json::json!({ "tool_chain": tool_chain, "libs": ["m"], "lib_paths": ["/opt/lib"] }));
+        assert_eq!(app.tool_chain.obj_file_ext(), "obj");
+        assert_eq!(app.tool_chain.compiler_output_flag(), "-o=");
+        assert_eq!(app.tool_chain.archiver(), "tar");
+        let args = link_args(&app, &["main.obj"]);
+        assert_eq!(args[0], "tcc");
+        assert!(args.contains(&"--lib=m".to_string()) && args.contains(&"--libdir=/opt/lib".to_string()));
+
+        // unset flags fall back to gcc's
+        let bare: ToolChain = serde_json::from_value(serde_json::json!({ "compiler": "tcc", "linker": "tcc" })).unwrap();
+        assert_eq!(bare.compiler_include_flag(), ToolChain::Gcc.compiler_include_flag());
+        assert_eq!(bare.obj_file_ext(), "o");
+    }
+
+    #[test]
+    fn whole_archive_libs_are_wrapped_per_linker() {
+        let libs = serde_json::json!(["plugins"]);
+        let gnu = link_args(&minimal(serde_json::json!({ "whole_archive_libs": libs, "target": "LinuxX64" })), &["main.o"]);
+        let start = gnu.iter().position(|arg| arg == "-Wl,--whole-archive").unwrap();
+        assert_eq!(gnu[start..start + 3], ["-Wl,--whole-archive", "-lplugins", "-Wl,--no-whole-archive"]);
+
+        let msvc = link_args(&minimal(serde_json::json!({ "whole_archive_libs": libs, "tool_chain": "Msvc" })), &["main.obj"]);
+        assert!(msvc.contains(&"/WHOLEARCHIVE:plugins.lib".to_string()));
+
+        let dir = crate::scratch_dir("whole_archive");
+        std::fs::write(dir.join("libplugins.a"), "").unwrap();
+        let macos = minimal(serde_json::json!({
+            "whole_archive_libs": libs, "lib_paths": [dir], "tool_chain": "Zig", "zig_target": "aarch64-macos-none",
+        }));
+        let force_load = format!("-Wl,-force_load,{}", dir.join("libplugins.a").display());
+        assert!(link_args(&macos, &["main.o"]).contains(&force_load));
+    }
+
+    #[test]
+    fn outputs_can_be_suffixed_with_the_profile() {
+        let app = minimal(serde_json::json!({ "output": "out/app", "output_suffix_by_profile": true, "target": "LinuxX64" }));
+        assert_eq!(app.output(), PathBuf::from("out/app-debug"));
+        let release = minimal(serde_json::json!({ "output": "out/app", "output_suffix_by_profile": true, "target": "LinuxX64", "opt_level": "Release" }));
+        assert_eq!(release.output(), PathBuf::from("out/app-release"));
+        let lib = minimal(serde_json::json!({ "output": "out/app", "output_suffix_by_profile": true, "target": "LinuxX64", "type": "StaticLib" }));
+        assert_eq!(lib.output(), PathBuf::from("out/libapp-debug.a"));
+        let windows = minimal(serde_json::json!({ "output": "out/app", "output_suffix_by_profile": true, "target": "WindowsX64" }));
+        assert_eq!(windows.output(), PathBuf::from("out/app-debug.exe"));
+
+        let mut app = minimal(serde_json::json!({}));
+        app.add_dependency(&minimal(serde_json::json!({ "output": "out/foo", "output_suffix_by_profile": true, "type": "StaticLib" })));
+        assert_eq!(app.libs, vec!["foo-debug".to_string()]);
+    }
+
+    #[test]
+    fn map_files_use_each_linkers_flag() {
+        let gnu = minimal(serde_json::json!({ "output": "out/app", "map_file": true, "target": "LinuxX64" }));
+        assert!(link_args(&gnu, &["main.o"]).contains(&"-Wl,-Map=out/app.map".to_string()));
+
+        let msvc = minimal(serde_json::json!({ "output": "out/app", "map_file": "maps/app.map", "tool_chain": "Msvc" }));
+        assert!(link_args(&msvc, &["main.obj"]).contains(&"/MAP:maps/app.map".to_string()));
+
+        let macos = minimal(serde_json::json!({
+            "output": "out/app", "map_file": true, "tool_chain": "Zig", "zig_target": "aarch64-macos-none",
+        }));
+        assert!(link_args(&macos, &["main.o"]).contains(&"-Wl,-map,out/app.map".to_string()));
+
+        let off = minimal(serde_json::json!({ "map_file": false }));
+        assert!(!link_args(&off, &["main.o"]).iter().any(|arg| arg.contains("-Map")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn graphs_sharing_a_source_compile_it_once() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = crate::scratch_dir("object_cache");
+        let compiler = dir.join("logging-cc");
+        let log = dir.join("calls.log");
+        std::fs::write(&compiler, format!("#!/bin/sh\necho \"$@\" >> '{}'\nexec gcc \"$@\"\n", log.display())).unwrap();
+        std::fs::set_permissions(&compiler, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let cache = Arc::new(ObjectCache::default());
+        let app = |name: &str, env: serde_json::Value| {
+            let mut graph = graph_in(&dir, &["main.c"], serde_json::json!({
+                "tool_chain": { "compiler": compiler, "linker": "gcc" }, "output": dir.join(name), "env": env,
+            }));
+            graph.object_cache = Some(cache.clone());
+            graph.quiet = true;
+            graph
+        };
+        let (first, second) = (app("first", serde_json::json!({})), app("second", serde_json::json!({})));
+        let third = app("third", serde_json::json!({ "CBUILD_TEST": "1" }));
+        std::fs::write(dir.join("main.c"), "int main(void) { return 0; }\n").unwrap();
+        let (a, b) = tokio::join!(first.build(), second.build());
+        assert!(a.unwrap().is_file() && b.unwrap().is_file());
+        // the version probe runs the compiler too
+        let compiles = || std::fs::read_to_string(&log).unwrap().lines().filter(|call| call.starts_with("-c ")).count();
+        assert_eq!(compiles(), 1);
+
+        let shared = dir.join(".cargoc/debug").join(Graph::SHARED_OBJ_DIR);
+        let objects = std::fs::read_dir(&shared).unwrap().filter_map(|entry| entry.ok()).map(|entry| entry.path());
+        assert_eq!(objects.filter(|path| path.extension().is_some_and(|ext| ext == "o")).count(), 1);
+
+        // a different environment may change what the compiler produces
+        third.build().await.unwrap();
+        assert_eq!(compiles(), 2);
+    }
+
+    #[tokio::test]
+    async fn bin_dir_links_away_from_the_output() {
+        let dir = crate::scratch_dir("bin_dir");
+        let graph = graph_in(&dir, &[], serde_json::json!({ "bin_dir": true, "target": "LinuxX64" }));
+        let built = graph.output();
+        assert_eq!(built, dir.join(".cargoc").join(Graph::BIN_DIR).join("app"));
+        std::fs::create_dir_all(built.parent().unwrap()).unwrap();
+        std::fs::write(&built, "binary").unwrap();
+        assert_eq!(graph.install(&built).await.unwrap(), dir.join("app"));
+        assert_eq!(std::fs::read_to_string(dir.join("app")).unwrap(), "binary");
+
+        let custom = graph_in(&dir, &[], serde_json::json!({ "bin_dir": dir.join("out"), "target": "LinuxX64" }));
+        assert_eq!(custom.output(), dir.join("out/app"));
+        let off = graph_in(&dir, &[], serde_json::json!({ "bin_dir": false, "target": "LinuxX64" }));
+        assert_eq!(off.output(), dir.join("app"));
+    }
+
+    #[test]
+    fn compile_flags_stay_off_links() {
+        let graph = minimal(serde_json::json!({
+            "args": { "custom": ["-pthread"], "compile_flags": ["-fno-exceptions"], "link_flags": ["-Wl,--as-needed"] },
+        }));
+        let link = link_args(&graph, &["main.o"]);
+        assert!(link.contains(&"-pthread".to_string()) && link.contains(&"-Wl,--as-needed".to_string()));
+        assert!(!link.contains(&"-fno-exceptions".to_string()));
+    }
+
+    #[test]
+    fn frameworks_are_linked_on_macos_only() {
+        let frameworks = serde_json::json!({ "frameworks": ["Cocoa"], "framework_paths": ["/opt/Frameworks"] });
+        let mut macos = frameworks.clone();
+        macos.as_object_mut().unwrap().extend([
+            ("tool_chain".to_string(), serde_json::json!("Zig")),
+            ("zig_target".to_string(), serde_json::json!("aarch64-macos-none")),
+        ]);
+        let args = link_args(&minimal(macos), &["main.o"]);
+        let framework = args.iter().position(|arg| arg == "-framework").unwrap();
+        assert_eq!(args[framework + 1], "Cocoa");
+        assert!(args.contains(&"-F/opt/Frameworks".to_string()));
+
+        let mut linux = frameworks;
+        linux.as_object_mut().unwrap().insert("target".to_string(), serde_json::json!("LinuxX64"));
+        let args = link_args(&minimal(linux), &["main.o"]);
+        assert!(!args.iter().any(|arg| arg == "-framework" || arg.starts_with("-F")));
+    }
+
+    #[test]
+    fn other_unixes_build_like_linux() {
+        if cfg!(unix) {
+            let expected = match std::env::consts::OS {
+                "linux" => Os::Linux,
+                "macos" => Os::MacOs,
+                _ => Os::UnixLike,
+            };
+            assert_eq!(Os::current(), expected);
+            assert_ne!(ToolChain::platform_default(), ToolChain::Msvc);
+        }
+
+        let freebsd = serde_json::json!({
+            "tool_chain": "Zig", "zig_target": "x86_64-freebsd-none", "type": "DynLib", "output": "foo", "rpaths": ["$ORIGIN"],
+        });
+        let lib = minimal(freebsd);
+        assert_eq!(lib.target_os(), Os::UnixLike);
+        assert_eq!(lib.output(), PathBuf::from("libfoo.so"));
+        assert!(link_args(&lib, &["foo.o"]).contains(&"-Wl,-rpath,$ORIGIN".to_string()));
+    }
+
+    #[tokio::test]
+    async fn every_missing_tool_is_reported() {
+        let dir = crate::scratch_dir("validate_tools");
+        let tool_chain = serde_json::json!({ "compiler": "cbuild-no-such-cc", "linker": "cbuild-no-such-ld" });
+        let graph = graph_in(&dir, &["main.c"], serde_json::json!({ "tool_chain": tool_chain }));
+        let error = BuildError::from(graph.validate_tools().await.unwrap_err());
+        let BuildError::Multiple(errors) = error else {
+            panic!("expected both tools, got {error}");
+        };
+        let tools = errors
+            .iter()
+            .map(|error| match error {
+                BuildError::ToolchainNotFound { tool } => tool.as_str(),
+                error => panic!("unexpected {error}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(tools, ["cbuild-no-such-cc", "cbuild-no-such-ld"]);
+
+        assert!(graph_in(&dir, &["main.c"], serde_json::json!({})).validate_tools().await.is_ok());
+    }
+
+    #[test]
+    fn msvc_links_libraries_by_file_name() {
+        let msvc = minimal(serde_json::json!({ "tool_chain": "Msvc", "libs": ["user32", "vendor/foo.lib"], "lib_paths": ["C:/libs"] }));
+        let args = link_args(&msvc, &["main.obj"]);
+        for expected in ["user32.lib", "vendor/foo.lib", "/LIBPATH:C:/libs"] {
+            assert!(args.contains(&expected.to_string()), "{expected} missing from {args:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn changed_link_command_relinks() {
+        let dir = crate::scratch_dir("relink");
+        let mut first = graph_in(&dir, &["main.c"], serde_json::json!({}));
+        let mut stripped = graph_in(&dir, &["main.c"], serde_json::json!({ "args": { "link_flags": ["-s"] } }));
+        std::fs::write(dir.join("main.c"), "int main(void) { return 0; }\n").unwrap();
+        first.quiet = true;
+        stripped.quiet = true;
+        first.build().await.unwrap();
+
+        let files = objects(&[]);
+        let fingerprint = std::fs::read_to_string(first.link_fingerprint_path()).unwrap();
+        assert_eq!(first.should_recompile(&files, &fingerprint).unwrap(), Decision::UpToDate);
+        let changed = format!("{fingerprint} -s");
+        assert_eq!(first.should_recompile(&files, &changed).unwrap(), Decision::Rebuild(Reason::FlagsChanged));
+
+        stripped.build().await.unwrap();
+        assert!(std::fs::read_to_string(first.link_fingerprint_path()).unwrap().contains(" -s"));
+    }
+
+    #[test]
+    fn braces_expand_to_every_alternative() {
+        assert_eq!(expand_braces("src/*.c"), ["src/*.c"]);
+        assert_eq!(expand_braces("src/*.{c,cpp}"), ["src/*.c", "src/*.cpp"]);
+        assert_eq!(expand_braces("{a,b/{c,d}}/*.h"), ["a/*.h", "b/c/*.h", "b/d/*.h"]);
+        assert_eq!(expand_braces("{a,b}{1,2}"), ["a1", "a2", "b1", "b2"]);
+        assert_eq!(expand_braces("src/{main.c"), ["src/{main.c"]);
+    }
+
+    #[test]
+    fn glob_matches_files_at_any_depth_once() {
+        let dir = crate::scratch_dir("expand_glob");
+        for file in ["main.c", "util/util.cpp", "util/deep/table.c", "util/notes.txt"] {
+            std::fs::create_dir_all(dir.join(file).parent().unwrap()).unwrap();
+            std::fs::write(dir.join(file), "").unwrap();
+        }
+        let pattern = format!("{}/**/*.{{c,cpp,c}}", dir.display());
+        let mut paths = expand_glob(&pattern).unwrap();
+        paths.sort();
+        assert_eq!(paths, vec![dir.join("main.c"), dir.join("util/deep/table.c"), dir.join("util/util.cpp")]);
+        assert!(Graph::is_glob(Path::new("src/{a,b}.c")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hooks_run_around_the_build_in_order() {
+        let dir = crate::scratch_dir("hooks");
+        let log = dir.join("hooks.log");
+        let mut graph = graph_in(&dir, &["main.c"], serde_json::json!({
+            "env": { "CBUILD_LOG": log, "CBUILD_APP": dir.join("app") },
+            "pre_build": "echo pre >> \"$CBUILD_LOG\"",
+            "post_build": ["test -f \"$CBUILD_APP\" && echo post >> \"$CBUILD_LOG\"", "echo done >> \"$CBUILD_LOG\""],
+        }));
+        let mut failing = graph_in(&dir, &["main.c"], serde_json::json!({ "env": { "CBUILD_LOG": log }, "pre_build": ["exit 3", "echo never >> \"$CBUILD_LOG\""] }));
+        std::fs::write(dir.join("main.c"), "int main(void) { return 0; }\n").unwrap();
+        graph.quiet = true;
+        graph.build().await.unwrap();
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "pre\npost\ndone\n");
+
+        failing.quiet = true;
+        let error = failing.build().await.unwrap_err().to_string();
+        assert!(error.contains("pre_build hook `exit 3` failed"), "{error}");
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "pre\npost\ndone\n");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn pre_build_hooks_can_generate_what_is_compiled() {
+        let dir = crate::scratch_dir("generated_sources");
+        let generated = dir.join("gen");
+        let mut graph = graph_in(&dir, &["main.c"], serde_json::json!({
+            "files": [dir.join("main.c"), generated.join("answer.c")],
+            "includes": [&generated],
+            "env": { "CBUILD_GEN": &generated },
+            "pre_build": [
+                "mkdir -p \"$CBUILD_GEN\"",
+                "echo 'int answer(void);' > \"$CBUILD_GEN/answer.h\"",
+                "echo 'int answer(void) { return 0; }' > \"$CBUILD_GEN/answer.c\"",
+            ],
+        }));
+        std::fs::write(dir.join("main.c"), "#include \"answer.h\"\nint main(void) { return answer(); }\n").unwrap();
+        graph.quiet = true;
+        assert_eq!(graph.build().await.unwrap(), dir.join("app"));
+        assert!(std::process::Command::new(dir.join("app")).status().unwrap().success());
+    }
+
+    #[test]
+    fn newer_libraries_relink() {
+        let dir = crate::scratch_dir("newer_library");
+        std::fs::create_dir_all(dir.join("lib")).unwrap();
+        let graph = graph_in(&dir, &[], serde_json::json!({ "target": "LinuxX64", "libs": ["foo", "m"], "lib_paths": [dir.join("lib")] }));
+        let set_modified = |path: &Path, secs: u64| {
+            let file = std::fs::File::options().create(true).truncate(false).write(true).open(path).unwrap();
+            file.set_modified(std::time::UNIX_EPOCH + Duration::from_secs(secs)).unwrap();
+        };
+        set_modified(&dir.join("lib/libfoo.a"), 100);
+        set_modified(&dir.join("app"), 200);
+        std::fs::create_dir_all(graph.link_fingerprint_path().parent().unwrap()).unwrap();
+        std::fs::write(graph.link_fingerprint_path(), "link").unwrap();
+        // `m` is not under `lib_paths`, so it is left to the linker
+        assert_eq!(graph.linked_libraries(), vec![dir.join("lib/libfoo.a")]);
+        assert_eq!(graph.should_recompile(&[], "link").unwrap(), Decision::UpToDate);
+
+        set_modified(&dir.join("lib/libfoo.a"), 300);
+        assert_eq!(graph.should_recompile(&[], "link").unwrap(), Decision::Rebuild(Reason::Newer(dir.join("lib/libfoo.a"))));
+
+        let msvc = graph_in(&dir, &[], serde_json::json!({ "tool_chain": "Msvc", "lib_paths": [dir.join("lib")] }));
+        std::fs::write(dir.join("lib/foo.lib"), "").unwrap();
+        assert_eq!(msvc.find_library("foo"), Some(dir.join("lib/foo.lib")));
+    }
+
+    #[test]
+    fn error_on_promotes_a_single_warning() {
+        let warning: WarningFlag = serde_json::from_value(serde_json::json!({ "ErrorOn": "unused-variable" })).unwrap();
+        assert_eq!(warning.enable_flag(&ToolChain::Gcc).as_deref(), Some("-Werror=unused-variable"));
+        assert_eq!(warning.disable_flag(&ToolChain::Clang).as_deref(), Some("-Wno-error=unused-variable"));
+        assert_eq!(warning.enable_flag(&ToolChain::Msvc), None);
+
+        let msvc = WarningFlag::ErrorOn("4101".to_string());
+        assert_eq!(msvc.enable_flag(&ToolChain::Msvc).as_deref(), Some("/we4101"));
+        assert_eq!(msvc.disable_flag(&ToolChain::Msvc), None);
+    }
+
+    #[tokio::test]
+    async fn prebuilt_objects_are_linked_as_they_are() {
+        let dir = crate::scratch_dir("prebuilt_objects");
+        let mut graph = graph_in(&dir, &["main.c"], serde_json::json!({ "objects": [dir.join("foo.o")] }));
+        std::fs::write(dir.join("foo.c"), "int foo(void) { return 0; }\n").unwrap();
+        std::fs::write(dir.join("main.c"), "int foo(void);\nint main(void) { return foo(); }\n").unwrap();
+        let status = std::process::Command::new("gcc").arg("-c").arg(dir.join("foo.c")).arg("-o").arg(dir.join("foo.o")).status().unwrap();
+        assert!(status.success());
+        graph.quiet = true;
+        assert_eq!(graph.build().await.unwrap(), dir.join("app"));
+        assert!(std::process::Command::new(dir.join("app")).status().unwrap().success());
+
+        let missing = graph_in(&dir, &["main.c"], serde_json::json!({ "objects": [dir.join("missing.o")] }));
+        assert!(missing.validate().unwrap_err().to_string().contains("prebuilt object not found"));
+    }
+
+    #[tokio::test]
+    async fn graphs_without_sources_fail_before_linking() {
+        let dir = crate::scratch_dir("no_sources");
+        std::fs::create_dir_all(dir.join("src")).unwrap();
+        let mut graph = graph_in(&dir, &[], serde_json::json!({ "files": [dir.join("src/*.c")] }));
+        graph.quiet = true;
+        let error = graph.build().await.unwrap_err().to_string();
+        assert_eq!(error, format!("no source files to compile for output `{}`", dir.join("app").display()));
+    }
+
+    #[tokio::test]
+    async fn dry_runs_write_nothing() {
+        let dir = crate::scratch_dir("dry_run");
+        let mut graph = graph_in(&dir, &["main.c", "util.c"], serde_json::json!({
+            "unity_batch_size": 2,
+            "pre_build": format!("touch {}", dir.join("hook").display()),
+        }));
+        graph.quiet = true;
+        graph.dry_run = true;
+        assert_eq!(graph.build().await.unwrap(), dir.join("app"));
+        assert_eq!(graph.install(&dir.join("app")).await.unwrap(), dir.join("app"));
+        for path in ["app", ".cargoc", "hook"] {
+            assert!(!dir.join(path).exists(), "{path} was written");
+        }
+    }
+
+    #[tokio::test]
+    async fn dry_runs_need_no_toolchain() {
+        let dir = crate::scratch_dir("dry_run_tools");
+        let tool_chain = serde_json::json!({ "compiler": "cbuild-no-such-cc", "linker": "cbuild-no-such-ld" });
+        let mut graph = graph_in(&dir, &["main.c"], serde_json::json!({ "tool_chain": tool_chain }));
+        graph.quiet = true;
+        graph.dry_run = true;
+        // reaching the link means every command was printed
+        assert_eq!(graph.build().await.unwrap(), dir.join("app"));
+        assert_eq!(link_args(&graph, &["main.o"])[0], "cbuild-no-such-ld");
+        assert!(!dir.join(".cargoc").exists());
+    }
+
+    #[test]
+    fn script_paths_are_rebased_onto_the_script_dir() {
+        let mut graph = minimal(serde_json::json!({
+            "files": ["src/main.c", "/abs/util.c", "@sources.txt"],
+            "includes": ["include"],
+            "lib_paths": ["lib", "/usr/lib"],
+            "output": "build/app",
+            "cache_dir": ".cache",
+            "bin_dir": "bin",
+            "map_file": "app.map",
+            "overrides": { "src/gen/*.c": { "warnings": [] } },
+        }));
+        graph.resolve_paths(Path::new("/project"));
+        assert_eq!(graph.files, ["/project/src/main.c", "/abs/util.c", "@/project/sources.txt"].map(PathBuf::from));
+        assert_eq!(graph.includes, [PathBuf::from("/project/include")]);
+        assert_eq!(graph.lib_paths, ["/project/lib", "/usr/lib"]);
+        assert_eq!(graph.output, PathBuf::from("/project/build/app"));
+        assert_eq!(graph.cache_dir(), PathBuf::from("/project/.cache"));
+        assert_eq!(graph.bin_dir, Some(BinDir::Path(PathBuf::from("/project/bin"))));
+        assert_eq!(graph.map_file, Some(MapFile::Path(PathBuf::from("/project/app.map"))));
+        assert!(graph.overrides.contains_key("/project/src/gen/*.c"));
+
+        let mut unchanged = minimal(serde_json::json!({ "files": ["main.c"] }));
+        unchanged.resolve_paths(Path::new(""));
+        assert_eq!(unchanged.files, [PathBuf::from("main.c")]);
+    }
+
+    #[tokio::test]
+    async fn thin_lto_links_keep_a_cache() {
+        let dir = crate::scratch_dir("lto_cache");
+        let clang = graph_in(&dir, &["main.c"], serde_json::json!({ "tool_chain": "Clang", "lto": "Thin", "target": "LinuxX64" }));
+        let flag = crate::path_arg("-Wl,--thinlto-cache-dir=", dir.join(".cargoc/lto")).to_string_lossy().into_owned();
+        assert!(link_args(&clang, &["main.o"]).contains(&flag));
+
+        // gcc falls back to full LTO, which has no such cache
+        let gcc = graph_in(&dir, &["main.c"], serde_json::json!({ "lto": "Thin", "target": "LinuxX64" }));
+        assert!(!link_args(&gcc, &["main.o"]).iter().any(|arg| arg.contains("thinlto")));
+    }
+
+    #[tokio::test]
+    async fn sysroot_reaches_compiles_and_links() {
+        let dir = crate::scratch_dir("sysroot");
+        let gcc = graph_in(&dir, &["main.c"], serde_json::json!({ "sysroot": "/opt/sysroot" }));
+        assert_eq!(gcc.input_files().await.unwrap()[0].options().sysroot, Some(PathBuf::from("/opt/sysroot")));
+        assert!(link_args(&gcc, &["main.o"]).contains(&"--sysroot=/opt/sysroot".to_string()));
+
+        let tool_chain = serde_json::json!({ "compiler": "arm-none-eabi-gcc", "linker": "arm-none-eabi-gcc", "sysroot": "/opt/arm" });
+        let custom = graph_in(&dir, &["main.c"], serde_json::json!({ "tool_chain": tool_chain }));
+        assert!(link_args(&custom, &["main.o"]).contains(&"--sysroot=/opt/arm".to_string()));
+        let own = graph_in(&dir, &["main.c"], serde_json::json!({ "tool_chain": tool_chain, "sysroot": "/opt/own" }));
+        assert_eq!(own.input_files().await.unwrap()[0].options().sysroot, Some(PathBuf::from("/opt/own")));
+
+        let msvc = graph_in(&dir, &["main.c"], serde_json::json!({ "tool_chain": "Msvc", "sysroot": "/opt/sysroot" }));
+        assert!(!link_args(&msvc, &["main.obj"]).iter().any(|arg| arg.starts_with("--sysroot")));
+    }
+
+    #[test]
+    fn excludes_match_however_the_path_is_spelled() {
+        let dir = crate::scratch_dir("excludes");
+        std::fs::create_dir_all(dir.join("src/gen")).unwrap();
+        std::fs::write(dir.join("src/gen/table.c"), "").unwrap();
+        let relative = minimal(serde_json::json!({ "excludes": ["./src/gen", "src/*_test.c"] }));
+        assert!(relative.is_excluded(Path::new("src/gen/table.c")));
+        assert!(relative.is_excluded(Path::new("./src/util_test.c")));
+        assert!(!relative.is_excluded(Path::new("src/util.c")));
+
+        // `..` is only resolved by canonicalizing, which needs both paths to exist
+        let absolute = minimal(serde_json::json!({ "excludes": [dir.join("src/gen")] }));
+        assert!(absolute.is_excluded(&dir.join("src/../src/gen/table.c")));
+        assert!(!absolute.is_excluded(&dir.join("src/../src/gen/missing.c")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn locked_tools_are_retried_but_failures_are_not() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = crate::scratch_dir("link_retries");
+        // spawning a file without the executable bit fails with access denied, like a locked one
+        std::fs::write(dir.join("locked"), "").unwrap();
+        let mut graph = minimal(serde_json::json!({}));
+        graph.link_retries = 2;
+        let start = Instant::now();
+        let error = graph.run_with_retries(&mut Command::new(dir.join("locked")), "linker").await.unwrap_err();
+        assert!(Graph::is_transient(&error));
+        assert!(start.elapsed() >= Duration::from_millis(200 + 400));
+
+        // a lock released before the next attempt lets the retry succeed
+        let released = dir.join("released");
+        std::fs::write(&released, "#!/bin/sh\nexit 0\n").unwrap();
+        let unlock = released.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            std::fs::set_permissions(unlock, std::fs::Permissions::from_mode(0o755)).unwrap();
+        });
+        let start = Instant::now();
+        let status = graph.run_with_retries(&mut Command::new(&released), "linker").await.unwrap();
+        assert!(status.success() && start.elapsed() >= Duration::from_millis(200));
+
+        let start = Instant::now();
+        let status = graph.run_with_retries(&mut Command::new("false"), "linker").await.unwrap();
+        assert!(!status.success() && start.elapsed() < Duration::from_millis(200));
+        assert!(!Graph::is_transient(&anyhow::Error::from(BuildError::ToolchainNotFound { tool: "ld".to_string() })));
+    }
+
+    #[tokio::test]
+    async fn outputs_in_missing_directories_link() {
+        let dir = crate::scratch_dir("nested_output");
+        let mut app = graph_in(&dir, &["main.c"], serde_json::json!({ "output": dir.join("build/bin/app") }));
+        let mut lib = graph_in(&dir, &["main.c"], serde_json::json!({ "type": "StaticLib", "output": dir.join("build/lib/foo") }));
+        std::fs::write(dir.join("main.c"), "int main(void) { return 0; }\n").unwrap();
+        app.quiet = true;
+        lib.quiet = true;
+        assert_eq!(app.build().await.unwrap(), dir.join("build/bin/app"));
+        assert!(dir.join("build/bin/app").is_file());
+        assert!(lib.build().await.unwrap().is_file());
+    }
+
+    #[tokio::test]
+    async fn objects_keep_the_source_extension() {
+        let dir = crate::scratch_dir("object_names");
+        let graph = graph_in(&dir, &["util.c", "util.cpp"], serde_json::json!({}));
+        let mut objects = graph.input_files().await.unwrap().iter().map(|file| file.output_path.clone()).collect::<Vec<_>>();
+        objects.sort();
+        let obj_dir = graph.obj_dir();
+        assert_eq!(objects, vec![obj_dir.join("util.c.o"), obj_dir.join("util.cpp.o")]);
+
+        let msvc = minimal(serde_json::json!({ "tool_chain": "Msvc" }));
+        assert_eq!(msvc.with_obj_ext(Path::new("obj/main.c")), PathBuf::from("obj/main.c.obj"));
+    }
+
+    #[tokio::test]
+    async fn failures_keep_their_cause() {
+        let dir = crate::scratch_dir("build_errors");
+        let mut broken = graph_in(&dir, &["broken.c"], serde_json::json!({}));
+        let mut unlinked = graph_in(&dir, &["main.c"], serde_json::json!({}));
+        std::fs::write(dir.join("broken.c"), "int main(void) { return missing; }\n").unwrap();
+        std::fs::write(dir.join("main.c"), "int foo(void);\nint main(void) { return foo(); }\n").unwrap();
+        let recorder = Arc::new(crate::Recorder::default());
+        for graph in [&mut broken, &mut unlinked] {
+            graph.quiet = true;
+            graph.reporter = Some(recorder.clone());
+        }
+        match broken.build().await.unwrap_err() {
+            BuildError::CompileFailed { file, stderr } => {
+                assert_eq!(file, dir.join("broken.c"));
+                assert!(stderr.contains("missing"), "{stderr}");
+            }
+            error => panic!("unexpected {error}"),
+        }
+        assert!(matches!(unlinked.build().await.unwrap_err(), BuildError::LinkFailed { output } if output == dir.join("app")));
+
+        let mut missing = minimal(serde_json::json!({ "files": [dir.join("gone.c")] }));
+        missing.quiet = true;
+        assert!(matches!(missing.build().await.unwrap_err(), BuildError::SourceMissing { path } if path == dir.join("gone.c")));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hung_links_are_killed_after_the_timeout() {
+        let mut graph = minimal(serde_json::json!({}));
+        graph.timeout = Some(Duration::from_millis(200));
+        let mut sleep = Command::new("sleep");
+        sleep.arg("30");
+        let start = Instant::now();
+        let error = BuildError::from(graph.run_with_retries(&mut sleep, "linker").await.unwrap_err());
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(matches!(&error, BuildError::Timeout { what, .. } if what == "linker for `a`"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn list_files_expand_to_the_sources_they_name() {
+        let dir = crate::scratch_dir("list_files");
+        let list = dir.join("build/sources.txt");
+        let graph = graph_in(&dir, &["src/main.c", "src/util.c", "extra.c"], serde_json::json!({
+            "files": [dir.join("extra.c"), format!("@{}", list.display())],
+        }));
+        std::fs::create_dir_all(dir.join("build")).unwrap();
+        std::fs::write(&list, "# generated\n../src/main.c\n\n  ../src/util.c  \n").unwrap();
+        let sources = graph.collect_sources().await.unwrap();
+        assert_eq!(sources, vec![dir.join("extra.c"), dir.join("build/../src/main.c"), dir.join("build/../src/util.c")]);
+
+        let missing = graph_in(&dir, &[], serde_json::json!({ "files": [format!("@{}", dir.join("missing.txt").display())] }));
+        assert!(missing.collect_sources().await.unwrap_err().to_string().starts_with("failed to read source list"));
+    }
+
+    #[tokio::test]
+    async fn platform_files_are_compiled_for_their_os_only() {
+        let dir = crate::scratch_dir("platform_files");
+        let platform = |target: &str| {
+            graph_in(&dir, &["main.c", "linux.c", "windows.c"], serde_json::json!({
+                "files": [dir.join("main.c")],
+                "platform_files": { "linux": [dir.join("linux.c")], "windows": [dir.join("windows.c")] },
+                "target": target,
+            }))
+        };
+        assert_eq!(platform("LinuxX64").collect_sources().await.unwrap(), vec![dir.join("main.c"), dir.join("linux.c")]);
+        assert_eq!(platform("WindowsX64").collect_sources().await.unwrap(), vec![dir.join("main.c"), dir.join("windows.c")]);
+
+        let mut relative = minimal(serde_json::json!({ "platform_files": { "macos": ["mac.m"] } }));
+        relative.resolve_paths(Path::new("/project"));
+        assert_eq!(relative.platform_files.macos, vec![PathBuf::from("/project/mac.m")]);
+    }
+
+    #[test]
+    fn source_date_epoch_reaches_tools_unless_overridden() {
+        let graph = minimal(serde_json::json!({ "env": { "CBUILD_TEST": "1" } }));
+        assert_eq!(graph.tool_env().get("SOURCE_DATE_EPOCH").cloned(), crate::source_date_epoch());
+        assert_eq!(graph.tool_env().get("CBUILD_TEST").map(String::as_str), Some("1"));
+
+        let pinned = minimal(serde_json::json!({ "env": { "SOURCE_DATE_EPOCH": "0" } }));
+        let has_epoch = |cmd: &Command| cmd.as_std().get_envs().any(|(key, value)| key == "SOURCE_DATE_EPOCH" && value == Some("0".as_ref()));
+        assert!(has_epoch(&pinned.link_command(&objects(&["main.o"]))));
+        assert!(has_epoch(&pinned.archive_command(&objects(&["main.o"]))));
+    }
+
+    /// Counts the events logged while it is the default subscriber.
+    #[derive(Default)]
+    struct EventCounter(std::sync::atomic::AtomicUsize);
+
+    impl tracing::Subscriber for EventCounter {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+        fn event(&self, _: &tracing::Event<'_>) {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        }
+        fn enter(&self, _: &tracing::span::Id) {}
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[tokio::test]
+    async fn each_compiler_version_is_logged_once() {
+        let counter = Arc::new(EventCounter::default());
+        let _default = tracing::subscriber::set_default(counter.clone());
+        let logged = || counter.0.load(std::sync::atomic::Ordering::SeqCst);
+        let tool_chain = |compiler: &str| -> ToolChain { serde_json::from_value(serde_json::json!({ "compiler": compiler, "linker": "ld" })).unwrap() };
+
+        tool_chain("cbuild-version-a-cc").log_version().await;
+        assert_eq!(logged(), 1);
+        tool_chain("cbuild-version-a-cc").log_version().await;
+        assert_eq!(logged(), 1);
+        tool_chain("cbuild-version-b-cc").log_version().await;
+        assert_eq!(logged(), 2);
+    }
+
+    #[test]
+    fn zig_objects_follow_the_target_abi() {
+        let object = |triple: &str| {
+            let zig = minimal(serde_json::json!({ "tool_chain": "Zig", "zig_target": triple, "src_dir": "src" }));
+            zig.object_path(Path::new("src/main.c")).strip_prefix(zig.obj_dir()).unwrap().to_path_buf()
+        };
+        assert_eq!(object("x86_64-windows-msvc"), PathBuf::from("main.c.obj"));
+        assert_eq!(object("x86_64-windows-gnu"), PathBuf::from("main.c.o"));
+        assert_eq!(object("aarch64-linux-musl"), PathBuf::from("main.c.o"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn interrupted_links_kill_the_linker() {
+        let dir = crate::scratch_dir("kill_on_drop");
+        let pid_file = dir.join("pid");
+        let graph = minimal(serde_json::json!({}));
+        let mut linker = Command::new("sh");
+        linker.arg("-c").arg(format!("echo $$ > '{}'; exec sleep 30", pid_file.display()));
+        let link = graph.run_with_retries(&mut linker, "linker");
+        assert!(tokio::time::timeout(Duration::from_millis(300), link).await.is_err());
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        let running = || {
+            std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()))
+                .is_ok_and(|stat| !stat.rsplit(')').next().unwrap_or_default().trim_start().starts_with('Z'))
+        };
+        let start = Instant::now();
+        while running() && start.elapsed() < Duration::from_secs(5) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        assert!(!running(), "the linker outlived its build");
+    }
+
+    #[test]
+    fn unprefixed_libraries_are_linked_by_path() {
+        let lib = minimal(serde_json::json!({ "type": "DynLib", "output": "out/foo", "target": "LinuxX64", "no_lib_prefix": true }));
+        assert_eq!(lib.output(), PathBuf::from("out/foo.so"));
+        let mut app = minimal(serde_json::json!({ "target": "LinuxX64" }));
+        app.add_dependency(&lib);
+        assert!(app.libs.is_empty() && app.lib_paths.is_empty());
+        // linked after the compiled objects, like a prebuilt one
+        assert_eq!(app.objects, vec![PathBuf::from("out/foo.so")]);
+
+        let windows = minimal(serde_json::json!({ "type": "StaticLib", "output": "out/foo", "target": "WindowsX64", "no_lib_prefix": true }));
+        let mut app = minimal(serde_json::json!({ "target": "WindowsX64" }));
+        app.add_dependency(&windows);
+        assert_eq!(app.libs, vec!["foo".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn builds_report_every_step_in_order() {
+        let dir = crate::scratch_dir("reporter");
+        let mut graph = graph_in(&dir, &["main.c"], serde_json::json!({}));
+        std::fs::write(dir.join("main.c"), "int main(void) { return 0; }\n").unwrap();
+        let recorder = Arc::new(crate::Recorder::default());
+        graph.reporter = Some(recorder.clone());
+        graph.quiet = true;
+        graph.build().await.unwrap();
+
+        let messages = recorder.0.lock().unwrap();
+        let reasons = messages.iter().map(|message| message["reason"].as_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(reasons, ["compile-started", "compile-finished", "link-finished"]);
+        assert_eq!(messages[1]["file"], serde_json::json!(dir.join("main.c")));
+        assert_eq!(messages[2]["output"], serde_json::json!(dir.join("app")));
+        assert!(messages[1]["success"].as_bool().unwrap() && messages[2]["success"].as_bool().unwrap());
+    }
+
+    #[test]
+    fn linker_args_go_before_or_after_the_libraries() {
+        let linker_args = serde_json::json!(["-Wl,--start-group", "-Wl,--end-group"]);
+        let before = minimal(serde_json::json!({ "target": "LinuxX64", "libs": ["foo"], "linker_args": linker_args }));
+        let args = link_args(&before, &["main.o"]);
+        let position = |arg: &str| args.iter().position(|candidate| candidate == arg).unwrap();
+        assert!(position("main.o") < position("-Wl,--start-group"));
+        assert!(position("-Wl,--start-group") < position("-Wl,--end-group") && position("-Wl,--end-group") < position("-lfoo"));
+
+        let after = minimal(serde_json::json!({
+            "target": "LinuxX64", "libs": ["foo"], "linker_args": linker_args, "linker_args_position": "AfterLibs",
+        }));
+        let args = link_args(&after, &["main.o"]);
+        let position = |arg: &str| args.iter().position(|candidate| candidate == arg).unwrap();
+        assert!(position("-lfoo") < position("-Wl,--start-group"));
+
+        let lib = minimal(serde_json::json!({ "type": "StaticLib", "linker_args": linker_args }));
+        assert!(!lib.archive_command(&objects(&["main.o"])).arguments().iter().any(|arg| arg.starts_with("-Wl")));
+    }
+
+    #[test]
+    fn gc_sections_let_the_linker_drop_unused_code() {
+        let gcc = minimal(serde_json::json!({ "target": "LinuxX64", "gc_sections": true }));
+        assert!(link_args(&gcc, &["main.o"]).contains(&"-Wl,--gc-sections".to_string()));
+        let msvc = minimal(serde_json::json!({ "tool_chain": "Msvc", "gc_sections": true }));
+        assert_eq!(link_args(&msvc, &["main.obj"]).iter().filter(|arg| *arg == "/OPT:REF").count(), 1);
+        // stripping already passes `/OPT:REF`
+        let stripped = minimal(serde_json::json!({ "tool_chain": "Msvc", "opt_level": "Release", "gc_sections": true, "strip": true }));
+        assert_eq!(link_args(&stripped, &["main.obj"]).iter().filter(|arg| *arg == "/OPT:REF").count(), 1);
+        assert!(!link_args(&minimal(serde_json::json!({ "target": "LinuxX64" })), &["main.o"]).contains(&"-Wl,--gc-sections".to_string()));
+    }
+}
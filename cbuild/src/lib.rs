@@ -1,8 +1,115 @@
+pub mod error;
 pub mod file;
 pub mod graph;
+pub mod progress;
+pub mod reporter;
+pub mod response_file;
+pub mod timings;
+
+/// Spawns `cmd`, telling a `tool` missing from `PATH` apart from other failures.
+pub fn spawn(cmd: &mut tokio::process::Command, tool: &str) -> anyhow::Result<tokio::process::Child> {
+    cmd.spawn().map_err(|e| match e.kind() {
+        std::io::ErrorKind::NotFound => {
+            let program = cmd.as_std().get_program().to_string_lossy().into_owned();
+            tracing::debug!("{tool} `{program}` not found on PATH");
+            error::BuildError::ToolchainNotFound { tool: program }.into()
+        }
+        _ => anyhow::Error::new(e).context(format!("failed to spawn process: {:?}", cmd.as_std())),
+    })
+}
+
+/// Creates `path` and its parents without checking for them first, so graphs
+/// building into the same tree concurrently can't race between check and create.
+pub async fn create_dir_all(path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+    match tokio::fs::create_dir_all(path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// `SOURCE_DATE_EPOCH`, the timestamp reproducible builds pin embedded dates to,
+/// if set to a valid number of seconds. Read once, so an invalid value warns once.
+pub fn source_date_epoch() -> Option<String> {
+    static EPOCH: std::sync::LazyLock<Option<String>> = std::sync::LazyLock::new(|| {
+        let epoch = std::env::var("SOURCE_DATE_EPOCH").ok()?;
+        if epoch.is_empty() || !epoch.bytes().all(|byte| byte.is_ascii_digit()) {
+            tracing::warn!("ignoring SOURCE_DATE_EPOCH `{epoch}`, expected seconds since the epoch");
+            return None;
+        }
+        Some(epoch)
+    });
+    EPOCH.clone()
+}
+
+/// Whether `program` exists, either as a path or in one of the `PATH` directories.
+pub fn find_program(program: &str) -> bool {
+    let path = std::path::Path::new(program);
+    if path.components().count() > 1 {
+        return path.is_file();
+    }
+    let Some(paths) = std::env::var_os("PATH") else {
+        return false;
+    };
+    std::env::split_paths(&paths).any(|dir| {
+        let candidate = dir.join(program);
+        candidate.is_file()
+            || (cfg!(windows)
+                && candidate.extension().is_none()
+                && candidate.with_extension("exe").is_file())
+    })
+}
+
+/// Joins `flag` and `path` into one argument, e.g. `/Fo` and `C:\My Objects\a.obj`,
+/// without a lossy round trip through UTF-8.
+pub fn path_arg(flag: &str, path: impl AsRef<std::path::Path>) -> std::ffi::OsString {
+    let mut arg = std::ffi::OsString::from(flag);
+    arg.push(path.as_ref());
+    arg
+}
+
+/// A fresh, empty directory below the system's temporary directory for one test.
+#[cfg(test)]
+pub(crate) fn scratch_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("cbuild-test-{}-{name}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Keeps every reported message as its JSON value, and keeps diagnostics out of test output.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct Recorder(pub std::sync::Mutex<Vec<serde_json::Value>>);
+
+#[cfg(test)]
+impl reporter::Reporter for Recorder {
+    fn report(&self, message: &reporter::Message) {
+        self.0.lock().unwrap().push(serde_json::to_value(message).unwrap());
+    }
+}
 
 pub trait CommandExt {
     fn display(&self) -> String;
+    /// The program followed by every argument, one entry each.
+    fn arguments(&self) -> Vec<String>;
+    /// Like `display`, but with arguments quoted for a POSIX shell where needed,
+    /// so `a b` and `"a" "b"` no longer read the same.
+    fn quoted(&self) -> String {
+        self.arguments()
+            .iter()
+            .map(|arg| shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Wraps `arg` in single quotes unless it only contains characters a shell leaves alone.
+pub fn shell_quote(arg: &str) -> std::borrow::Cow<'_, str> {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_+=/.,:@%^".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        return arg.into();
+    }
+    format!("'{}'", arg.replace('\'', r"'\''")).into()
 }
 
 impl CommandExt for std::process::Command {
@@ -14,10 +121,86 @@ impl CommandExt for std::process::Command {
         });
         output.to_string_lossy().to_string()
     }
+
+    fn arguments(&self) -> Vec<String> {
+        std::iter::once(self.get_program())
+            .chain(self.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
 }
 
 impl CommandExt for tokio::process::Command {
     fn display(&self) -> String {
         self.as_std().display()
     }
+
+    fn arguments(&self) -> Vec<String> {
+        self.as_std().arguments()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_commands_keep_arguments_apart() {
+        let mut cmd = std::process::Command::new("gcc");
+        cmd.args(["-c", "my file.c", "-DNAME='x'", ""]);
+        assert_eq!(cmd.quoted(), r"gcc -c 'my file.c' '-DNAME='\''x'\''' ''");
+        assert_ne!(cmd.quoted(), {
+            let mut split = std::process::Command::new("gcc");
+            split.args(["-c", "my", "file.c", "-DNAME='x'", ""]);
+            split.quoted()
+        });
+    }
+
+    #[tokio::test]
+    async fn missing_tools_are_told_apart_from_failed_spawns() {
+        let mut missing = tokio::process::Command::new("cbuild-no-such-compiler");
+        let error = spawn(&mut missing, "compiler").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<error::BuildError>(),
+            Some(error::BuildError::ToolchainNotFound { tool }) if tool == "cbuild-no-such-compiler"
+        ));
+
+        let mut not_executable = tokio::process::Command::new(std::env::temp_dir());
+        let error = spawn(&mut not_executable, "compiler").unwrap_err();
+        assert!(error.downcast_ref::<error::BuildError>().is_none());
+        assert!(error.to_string().starts_with("failed to spawn process"));
+    }
+
+    #[test]
+    fn path_args_are_joined_without_a_utf8_round_trip() {
+        assert_eq!(
+            path_arg("/Fo", "C:/My Objects/a.obj"),
+            "/FoC:/My Objects/a.obj"
+        );
+        #[cfg(unix)]
+        {
+            use std::os::unix::ffi::{OsStrExt, OsStringExt};
+            let path = std::ffi::OsStr::from_bytes(b"obj/caf\xe9.o");
+            assert_eq!(
+                path_arg("-Wl,-Map=", path).into_vec(),
+                b"-Wl,-Map=obj/caf\xe9.o"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_dir_creation_never_races() {
+        let dir = scratch_dir("create_dir_all");
+        let path = dir.join("debug/obj/app/src");
+        let mut set = tokio::task::JoinSet::new();
+        for _ in 0..16 {
+            let path = path.clone();
+            set.spawn(async move { create_dir_all(path).await });
+        }
+        while let Some(created) = set.join_next().await {
+            created.unwrap().unwrap();
+        }
+        assert!(path.is_dir());
+        create_dir_all(&path).await.unwrap();
+    }
 }
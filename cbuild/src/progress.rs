@@ -0,0 +1,76 @@
+use std::{
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Counts the finished compilations of one build so log lines can be
+/// prefixed with `[N/M]`. Shared between the compile tasks.
+#[derive(Debug)]
+pub struct Progress {
+    total: usize,
+    finished: AtomicUsize,
+    compiled: AtomicUsize,
+    quiet: bool,
+}
+
+impl Progress {
+    pub fn new(total: usize, quiet: bool) -> Self {
+        Self {
+            total,
+            finished: AtomicUsize::new(0),
+            compiled: AtomicUsize::new(0),
+            quiet,
+        }
+    }
+
+    /// Marks `path` as done. Up-to-date files advance the counter silently.
+    pub fn finish(&self, path: &Path, compiled: bool) {
+        let finished = self.finished.fetch_add(1, Ordering::Relaxed) + 1;
+        if !compiled {
+            return;
+        }
+        self.compiled.fetch_add(1, Ordering::Relaxed);
+        if !self.quiet {
+            tracing::info!(
+                "[{finished}/{}] [Compiling]: {}",
+                self.total,
+                path.display()
+            );
+        }
+    }
+
+    pub fn finished(&self) -> usize {
+        self.finished.load(Ordering::Relaxed)
+    }
+
+    pub fn compiled(&self) -> usize {
+        self.compiled.load(Ordering::Relaxed)
+    }
+
+    pub fn report(&self) {
+        let compiled = self.compiled();
+        if compiled > 0 {
+            tracing::info!("Compiled {compiled} files");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn up_to_date_files_count_as_finished_only() {
+        let progress = Arc::new(Progress::new(8, true));
+        let tasks = (0..8).map(|i| {
+            let progress = progress.clone();
+            tokio::spawn(async move { progress.finish(Path::new("main.c"), i % 2 == 0) })
+        });
+        for task in tasks.collect::<Vec<_>>() {
+            task.await.unwrap();
+        }
+        assert_eq!(progress.finished(), 8);
+        assert_eq!(progress.compiled(), 4);
+    }
+}
@@ -0,0 +1,72 @@
+use serde::Serialize;
+use std::{fmt::Debug, io::Write, path::Path};
+
+/// A build event for tools that follow a build as it happens.
+#[derive(Debug, Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub enum Message<'a> {
+    CompileStarted {
+        file: &'a Path,
+    },
+    CompileFinished {
+        file: &'a Path,
+        success: bool,
+        seconds: f64,
+    },
+    LinkFinished {
+        output: &'a Path,
+        success: bool,
+        seconds: f64,
+    },
+    /// Compiler output for `file`, warnings and errors alike.
+    Diagnostic {
+        file: &'a Path,
+        message: &'a str,
+    },
+}
+
+/// Receives the events of every graph of a build. Messages normally logged for
+/// people, like diagnostics, are left to the reporter once one is set.
+pub trait Reporter: Debug + Send + Sync {
+    fn report(&self, message: &Message);
+}
+
+/// Prints every event as one JSON object per line on stdout.
+#[derive(Debug, Default)]
+pub struct JsonReporter;
+
+impl Reporter for JsonReporter {
+    fn report(&self, message: &Message) {
+        let Ok(mut line) = serde_json::to_string(message) else {
+            return;
+        };
+        line.push('\n');
+        _ = std::io::stdout().lock().write_all(line.as_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_are_tagged_by_reason() {
+        let message = Message::CompileFinished {
+            file: Path::new("src/main.c"),
+            success: true,
+            seconds: 0.5,
+        };
+        assert_eq!(
+            serde_json::to_value(&message).unwrap(),
+            serde_json::json!({ "reason": "compile-finished", "file": "src/main.c", "success": true, "seconds": 0.5 })
+        );
+        let message = Message::Diagnostic {
+            file: Path::new("src/main.c"),
+            message: "warning: unused",
+        };
+        assert_eq!(
+            serde_json::to_value(&message).unwrap()["reason"],
+            "diagnostic"
+        );
+    }
+}
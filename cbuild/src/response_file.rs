@@ -0,0 +1,143 @@
+use crate::graph::ToolChain;
+use anyhow::Result;
+use std::path::Path;
+use tokio::process::Command;
+
+/// Command lines longer than this are passed through a response file, well
+/// below the 32k limit of `CreateProcess`.
+pub const THRESHOLD: usize = 8000;
+
+/// Moves every argument of `cmd` after the first `keep` ones into a response
+/// file at `path` once the command line grows past [`THRESHOLD`]. The kept
+/// arguments are subcommands such as `zig cc` that have to stay in front.
+pub async fn apply(
+    cmd: Command,
+    keep: usize,
+    path: &Path,
+    tool_chain: &ToolChain,
+) -> Result<Command> {
+    let std = cmd.as_std();
+    let length = std.get_args().fold(std.get_program().len(), |length, arg| {
+        length + arg.len() + 1
+    });
+    if length <= THRESHOLD {
+        return Ok(cmd);
+    }
+
+    let mut contents = String::new();
+    std.get_args().skip(keep).for_each(|arg| {
+        contents.push_str(&quote(&arg.to_string_lossy(), tool_chain));
+        contents.push('\n');
+    });
+    tokio::fs::write(path, contents).await?;
+    tracing::debug!("[Response File]: {}", path.display());
+
+    let mut response = Command::new(std.get_program());
+    response.args(std.get_args().take(keep));
+    std.get_envs().for_each(|(key, value)| match value {
+        Some(value) => {
+            response.env(key, value);
+        }
+        None => {
+            response.env_remove(key);
+        }
+    });
+    if let Some(dir) = std.get_current_dir() {
+        response.current_dir(dir);
+    }
+    response.arg(format!("@{}", path.display()));
+    Ok(response)
+}
+
+/// Quotes `arg` for a response file. GNU style drivers treat every backslash
+/// as an escape, msvc only the ones in front of a quote.
+fn quote(arg: &str, tool_chain: &ToolChain) -> String {
+    if *tool_chain != ToolChain::Msvc {
+        if !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'' || c == '\\') {
+            return arg.to_string();
+        }
+        let mut quoted = String::from("\"");
+        arg.chars().for_each(|c| {
+            if c == '"' || c == '\\' {
+                quoted.push('\\');
+            }
+            quoted.push(c);
+        });
+        quoted.push('"');
+        return quoted;
+    }
+
+    if !arg.contains(|c: char| c.is_whitespace() || c == '"') {
+        return arg.to_string();
+    }
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    arg.chars().for_each(|c| match c {
+        '\\' => backslashes += 1,
+        '"' => {
+            quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+            quoted.push('"');
+            backslashes = 0;
+        }
+        c => {
+            quoted.push_str(&"\\".repeat(backslashes));
+            quoted.push(c);
+            backslashes = 0;
+        }
+    });
+    // backslashes in front of the closing quote would escape it
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CommandExt;
+
+    #[tokio::test]
+    async fn long_commands_move_into_a_response_file() {
+        let path = crate::scratch_dir("response_file").join("link.rsp");
+        let mut short = Command::new("zig");
+        short.args(["cc", "-o", "app"]);
+        let short = apply(short, 1, &path, &ToolChain::Zig).await.unwrap();
+        assert_eq!(short.arguments(), ["zig", "cc", "-o", "app"]);
+        assert!(!path.exists());
+
+        let mut long = Command::new("zig");
+        long.arg("cc").env("CBUILD_TEST", "1");
+        long.args((0..1000).map(|i| format!("obj/file {i}.o")));
+        let long = apply(long, 1, &path, &ToolChain::Zig).await.unwrap();
+        assert_eq!(
+            long.arguments(),
+            [
+                "zig".to_string(),
+                "cc".to_string(),
+                format!("@{}", path.display())
+            ]
+        );
+        assert_eq!(long.as_std().get_envs().count(), 1);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), 1000);
+        assert_eq!(contents.lines().next(), Some("\"obj/file 0.o\""));
+    }
+
+    #[test]
+    fn quoting_follows_the_toolchain() {
+        assert_eq!(quote("plain", &ToolChain::Gcc), "plain");
+        assert_eq!(
+            quote(r"C:\my dir\a.o", &ToolChain::Gcc),
+            r#""C:\\my dir\\a.o""#
+        );
+        assert_eq!(
+            quote(r"C:\my dir\a.o", &ToolChain::Msvc),
+            r#""C:\my dir\a.o""#
+        );
+        assert_eq!(
+            quote(r#"/DNAME="x y""#, &ToolChain::Msvc),
+            r#""/DNAME=\"x y\"""#
+        );
+        assert_eq!(quote(r"C:\my dir\", &ToolChain::Msvc), r#""C:\my dir\\""#);
+    }
+}
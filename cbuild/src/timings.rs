@@ -0,0 +1,87 @@
+use serde::{Deserialize, Serialize};
+use std::{path::PathBuf, sync::Mutex, time::Duration};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Step {
+    Compile,
+    Link,
+}
+
+/// Wall-clock duration of a single compile or link step.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Timing {
+    pub step: Step,
+    pub path: PathBuf,
+    pub seconds: f64,
+}
+
+/// Collects step timings from every graph of a build.
+#[derive(Debug, Default)]
+pub struct Timings {
+    entries: Mutex<Vec<Timing>>,
+}
+
+impl Timings {
+    pub fn record(&self, step: Step, path: PathBuf, duration: Duration) {
+        let timing = Timing {
+            step,
+            path,
+            seconds: duration.as_secs_f64(),
+        };
+        self.entries.lock().unwrap().push(timing);
+    }
+
+    /// Every recorded step, slowest first.
+    pub fn entries(&self) -> Vec<Timing> {
+        let mut entries = self.entries.lock().unwrap().clone();
+        entries.sort_by(|a, b| b.seconds.total_cmp(&a.seconds));
+        entries
+    }
+
+    pub fn report(&self, total: Duration) {
+        let entries = self.entries();
+        tracing::info!(
+            "[Timings]: compiled {} file(s)",
+            entries
+                .iter()
+                .filter(|timing| timing.step == Step::Compile)
+                .count()
+        );
+        entries.iter().for_each(|timing| {
+            tracing::info!(
+                "[Timings]: {:>8.3}s {:?} {}",
+                timing.seconds,
+                timing.step,
+                timing.path.display()
+            );
+        });
+        tracing::info!("[Timings]: {:>8.3}s total", total.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_slowest_first() {
+        let timings = Timings::default();
+        timings.record(
+            Step::Compile,
+            PathBuf::from("fast.o"),
+            Duration::from_millis(10),
+        );
+        timings.record(Step::Link, PathBuf::from("app"), Duration::from_millis(30));
+        timings.record(
+            Step::Compile,
+            PathBuf::from("slow.o"),
+            Duration::from_millis(20),
+        );
+        let paths = timings
+            .entries()
+            .into_iter()
+            .map(|timing| timing.path)
+            .collect::<Vec<_>>();
+        assert_eq!(paths, ["app", "slow.o", "fast.o"].map(PathBuf::from));
+    }
+}
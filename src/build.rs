@@ -1,34 +1,134 @@
 use anyhow::Result;
 use cbuild::graph::{OptimizationLevel, Os};
+use cbuild::file::{InputFile, ObjectCache};
+use cbuild::reporter::{JsonReporter, Reporter};
+use cbuild::timings::Timings;
 use cbuild::{graph::ToolChain, *};
 use mlua::prelude::*;
 use path_absolutize::Absolutize;
-use std::{ops::DerefMut, path::PathBuf};
+use std::{
+    ops::DerefMut,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::{process::Command, task::JoinHandle};
+use tokio::{
+    process::Command,
+    sync::{OnceCell, Semaphore},
+    task::{JoinHandle, JoinSet},
+};
 
 pub enum TargetHandle {
-    InProgress(JoinHandle<Result<PathBuf>>),
+    InProgress(JoinHandle<Result<PathBuf>>, Box<graph::Graph>),
     Done(Option<PathBuf>),
+    /// A build or install that failed; finishing the handle again raises the same error.
+    Failed(LuaError),
 }
 
 impl LuaUserData for TargetHandle {}
 
-#[derive(Debug)]
+impl TargetHandle {
+    /// Waits for the build and installs the result, leaving the handle `Done`.
+    /// A failed build is returned as an error so the script, and cargoc, fail with it.
+    async fn finish(&mut self) -> LuaResult<Option<PathBuf>> {
+        match self {
+            TargetHandle::InProgress(handle, graph) => {
+                let graph = graph.clone();
+                let built = handle.await.into_lua_err();
+                *self = TargetHandle::Done(None);
+                let installed = match built.and_then(|built| built.into_lua_err()) {
+                    Ok(built) => graph.install(&built).await.into_lua_err(),
+                    Err(e) => Err(e),
+                };
+                *self = match &installed {
+                    Ok(path) => TargetHandle::Done(Some(path.clone())),
+                    Err(e) => TargetHandle::Failed(e.clone()),
+                };
+                installed.map(Some)
+            }
+            TargetHandle::Done(path) => Ok(path.clone()),
+            TargetHandle::Failed(e) => Err(e.clone()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Graph {
     inner: graph::Graph,
+    skip: bool,
+    deps: Vec<Graph>,
+    built: Arc<OnceCell<PathBuf>>,
+}
+
+impl Graph {
+    /// Builds every dependency, concurrently, before linking against it.
+    /// Handles of the same graph share one build, so a dependency used twice
+    /// is only built once.
+    ///
+    /// Boxed because the recursion through `spawn` needs a nameable `Send` future.
+    fn build_once(&self) -> Pin<Box<dyn Future<Output = Result<PathBuf>> + Send + '_>> {
+        Box::pin(async move {
+            self.built
+                .get_or_try_init(|| async {
+                    let mut deps = JoinSet::new();
+                    self.deps.iter().cloned().for_each(|dep| {
+                        deps.spawn(async move { dep.build_once().await });
+                    });
+                    while let Some(dep) = deps.join_next().await {
+                        dep??;
+                    }
+                    let mut graph = self.inner.clone();
+                    self.deps
+                        .iter()
+                        .for_each(|dep| graph.add_dependency(&dep.inner));
+                    Ok(graph.build().await?)
+                })
+                .await
+                .cloned()
+        })
+    }
+
+    /// Builds the graph and copies it below the install prefix, if any.
+    pub async fn build_and_install(&self) -> Result<Option<PathBuf>> {
+        if self.skip {
+            return Ok(None);
+        }
+        let path = self.build_once().await?;
+        self.inner.install(&path).await.map(Some)
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.inner.is_executable()
+    }
 }
 
 impl LuaUserData for Graph {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
         methods.add_method("build", |_, this, _: ()| {
-            let graph = this.inner.clone();
-            Ok(TargetHandle::InProgress(tokio::spawn(async move {
-                graph.build().await
-            })))
+            if this.skip {
+                return Ok(TargetHandle::Done(None));
+            }
+            let graph = this.clone();
+            Ok(TargetHandle::InProgress(
+                tokio::spawn(async move { graph.build_once().await }),
+                Box::new(this.inner.clone()),
+            ))
+        });
+        methods.add_async_method("sources", async |_, this, _: ()| {
+            let sources = this.inner.collect_sources().await.into_lua_err()?;
+            sources
+                .iter()
+                .map(|source| {
+                    std::path::absolute(source)
+                        .map(|source| source.display().to_string())
+                        .into_lua_err()
+                })
+                .collect::<LuaResult<Vec<_>>>()
         });
         methods.add_async_method("build_and_install", async |_, this, _: ()| {
-            this.inner.build().await.into_lua_err()
+            this.build_and_install().await.into_lua_err()
         });
     }
 }
@@ -37,53 +137,486 @@ impl LuaUserData for Graph {
 pub struct Build {
     args: crate::Cli,
     binaries: Vec<Graph>,
+    jobs: Arc<Semaphore>,
+    timings: Option<Arc<Timings>>,
+    reporter: Option<Arc<dyn Reporter>>,
+    /// Objects compiled so far, shared so binaries reuse identical compiles.
+    objects: Arc<ObjectCache>,
+    /// Exit code of the first program started through `run` that failed.
+    run_failure: Mutex<Option<u8>>,
+    /// Only records the graphs the script declares, see [`Build::declare_only`].
+    declare_only: bool,
 }
 
 impl Build {
     pub fn new(args: crate::Cli) -> Self {
+        let jobs = Self::resolve_jobs(args.jobs);
         Self {
-            args,
             binaries: Vec::new(),
+            jobs: Arc::new(Semaphore::new(jobs.max(1))),
+            timings: (args.timings || args.timings_json.is_some())
+                .then(|| Arc::new(Timings::default())),
+            reporter: (args.message_format == crate::MessageFormat::Json)
+                .then(|| Arc::new(JsonReporter) as Arc<dyn Reporter>),
+            objects: Arc::default(),
+            run_failure: Mutex::new(None),
+            declare_only: false,
+            args,
+        }
+    }
+
+    /// A build that records every graph without building any, to learn what a script declares.
+    pub fn declare_only(args: crate::Cli) -> Self {
+        Self {
+            declare_only: true,
+            ..Self::new(args)
+        }
+    }
+
+    /// Picks the job count: `--jobs` > `CARGOC_JOBS` > `-jN` in `MAKEFLAGS` >
+    /// available parallelism.
+    pub fn resolve_jobs(jobs: Option<usize>) -> usize {
+        jobs.or_else(|| std::env::var("CARGOC_JOBS").ok()?.trim().parse().ok())
+            .or_else(|| Self::makeflags_jobs(&std::env::var("MAKEFLAGS").ok()?))
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|jobs| jobs.get())
+                    .unwrap_or(1)
+            })
+    }
+
+    /// Parses `-jN`, `-j N` or `--jobs=N` out of `MAKEFLAGS`.
+    fn makeflags_jobs(makeflags: &str) -> Option<usize> {
+        let mut flags = makeflags.split_whitespace();
+        while let Some(flag) = flags.next() {
+            let jobs = match flag {
+                "-j" | "--jobs" => flags.next(),
+                flag => flag
+                    .strip_prefix("-j")
+                    .or_else(|| flag.strip_prefix("--jobs=")),
+            };
+            if let Some(jobs) = jobs.and_then(|jobs| jobs.parse().ok()) {
+                return Some(jobs);
+            }
+        }
+        None
+    }
+
+    /// The exit code `cargoc` should finish with because a run program failed.
+    pub fn run_failure(&self) -> Option<u8> {
+        *self.run_failure.lock().unwrap()
+    }
+
+    fn record_run_failure(&self, code: u8) {
+        self.run_failure.lock().unwrap().get_or_insert(code);
+    }
+
+    /// Applies the command line overrides to `graph` and records it.
+    pub fn add_graph(&mut self, mut graph: graph::Graph, deps: Vec<Graph>) -> Graph {
+        if !self.args.relative_to_cwd
+            && let Some(script_dir) = self.args.build_scirpt.parent()
+        {
+            graph.resolve_paths(script_dir);
+        }
+        graph.full_rebuild = self.args.full_rebuild;
+        graph.mtime_only = self.args.mtime_only;
+        graph.explain = self.args.explain;
+        graph.dry_run = self.args.dry_run;
+        graph.link_retries = self
+            .args
+            .link_retries
+            .unwrap_or(if cfg!(windows) { 2 } else { 0 });
+        graph.timeout = self.args.timeout.map(std::time::Duration::from_secs);
+        graph.quiet = self.args.quiet;
+        graph.keep_going = self.args.keep_going;
+        graph.color = self.args.color.enabled();
+        graph.json_depfiles = self.args.depfile_format == crate::DepfileFormat::Json;
+        graph.env.extend(self.args.env.iter().cloned());
+        if let Some(prefix) = &self.args.prefix {
+            graph.install_prefix = Some(prefix.clone());
+        }
+        if let Some(target_dir) = &self.args.target_dir {
+            graph.cache_dir = Some(target_dir.clone());
+        }
+        graph.jobs = Some(self.jobs.clone());
+        graph.timings = self.timings.clone();
+        graph.reporter = self.reporter.clone();
+        graph.object_cache = Some(self.objects.clone());
+        let skip = !self.should_build() || !self.is_selected(&graph);
+        let graph = Graph {
+            inner: graph,
+            skip,
+            deps,
+            built: Arc::new(OnceCell::new()),
+        };
+        self.binaries.push(graph.clone());
+        graph
+    }
+
+    pub fn command(&self) -> &crate::Action {
+        &self.args.command
+    }
+
+    /// Graphs are only recorded, not built, when the script runs for
+    /// `clean`, to inspect a single file or to print the build graph or targets.
+    fn should_build(&self) -> bool {
+        !self.declare_only
+            && self.args.graph.is_none()
+            && self.args.list_targets.is_none()
+            && !matches!(
+                self.args.command,
+                crate::Action::Clean { .. }
+                    | crate::Action::Preprocess { .. }
+                    | crate::Action::Asm { .. }
+            )
+    }
+
+    /// Whether `graph` is one of the targets named on the command line, or no target was named.
+    fn is_selected(&self, graph: &graph::Graph) -> bool {
+        match &self.args.command {
+            crate::Action::Build { targets } if !targets.is_empty() => targets.contains(&graph.name()),
+            _ => true,
+        }
+    }
+
+    /// Requested targets that no declared binary is named after.
+    pub fn unknown_targets<'a>(&self, targets: &'a [String]) -> Vec<&'a str> {
+        targets
+            .iter()
+            .filter(|target| !self.binaries.iter().any(|graph| graph.inner.name() == **target))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Whether built executables should be started; never when nothing is really built.
+    pub fn wants_run(&self) -> bool {
+        self.args.command == crate::Action::Run && self.should_build() && !self.args.dry_run
+    }
+
+    /// Every declared binary with its type and toolchain, one per line or as JSON.
+    pub fn list_targets(&self, format: crate::ListFormat) -> Result<String> {
+        let graphs = self.binaries.iter().map(|graph| &graph.inner);
+        match format {
+            crate::ListFormat::Json => {
+                let targets = graphs
+                    .map(|graph| {
+                        serde_json::json!({
+                            "output": graph.output(),
+                            "type": graph.binary_type(),
+                            "tool_chain": graph.tool_chain(),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                Ok(serde_json::to_string_pretty(&targets)?)
+            }
+            crate::ListFormat::Text => Ok(graphs
+                .map(|graph| {
+                    let tool_chain = match graph.tool_chain() {
+                        ToolChain::Custom { compiler, .. } => compiler.clone(),
+                        tool_chain => format!("{tool_chain:?}"),
+                    };
+                    format!(
+                        "{}\t{:?}\t{tool_chain}",
+                        graph.output().display(),
+                        graph.binary_type()
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join("\n")),
+        }
+    }
+
+    /// Renders every declared graph as DOT or JSON: sources point at their
+    /// objects, objects and dependencies at the binary they are linked into.
+    pub async fn dump_graph(&self, format: crate::GraphFormat) -> Result<String> {
+        let mut binaries = Vec::new();
+        for graph in &self.binaries {
+            let objects = graph.inner.source_objects().await?;
+            let dependencies = graph
+                .deps
+                .iter()
+                .map(|dep| dep.inner.output())
+                .collect::<Vec<_>>();
+            binaries.push((graph.inner.output(), objects, dependencies));
+        }
+        match format {
+            crate::GraphFormat::Json => {
+                let binaries = binaries
+                    .iter()
+                    .map(|(output, objects, dependencies)| {
+                        serde_json::json!({
+                            "output": output,
+                            "objects": objects
+                                .iter()
+                                .map(|(source, object)| serde_json::json!({ "source": source, "object": object }))
+                                .collect::<Vec<_>>(),
+                            "dependencies": dependencies,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                Ok(serde_json::to_string_pretty(&binaries)?)
+            }
+            crate::GraphFormat::Dot => {
+                let quote = |path: &Path| {
+                    format!("\"{}\"", path.display().to_string().replace('"', "\\\""))
+                };
+                let mut dot = String::from("digraph cargoc {\n");
+                for (output, objects, dependencies) in &binaries {
+                    for (source, object) in objects {
+                        dot.push_str(&format!("    {} -> {};\n", quote(source), quote(object)));
+                        dot.push_str(&format!("    {} -> {};\n", quote(object), quote(output)));
+                    }
+                    for dependency in dependencies {
+                        dot.push_str(&format!(
+                            "    {} -> {};\n",
+                            quote(dependency),
+                            quote(output)
+                        ));
+                    }
+                }
+                dot.push('}');
+                Ok(dot)
+            }
+        }
+    }
+
+    /// The compile unit of `file` in the first graph compiling it.
+    async fn find_input_file(&self, file: &Path) -> Result<InputFile> {
+        for graph in &self.binaries {
+            if let Some(input) = graph.inner.find_input_file(file).await? {
+                return Ok(input);
+            }
+        }
+        Err(anyhow::anyhow!(
+            "`{}` is not a source of any declared binary",
+            file.display()
+        ))
+    }
+
+    pub async fn preprocess(&self, file: &Path, output: Option<&Path>) -> Result<()> {
+        self.find_input_file(file).await?.preprocess(output).await
+    }
+
+    pub async fn emit_assembly(&self, file: &Path) -> Result<PathBuf> {
+        self.find_input_file(file).await?.emit_assembly().await
+    }
+
+    /// Prints the timing summary and writes the JSON report, if requested.
+    pub async fn report_timings(&self, total: Duration) -> Result<()> {
+        let Some(timings) = &self.timings else {
+            return Ok(());
+        };
+        if self.args.timings {
+            timings.report(total);
+        }
+        if let Some(path) = &self.args.timings_json {
+            let report = serde_json::json!({
+                "total": total.as_secs_f64(),
+                "steps": timings.entries(),
+            });
+            tokio::fs::write(path, serde_json::to_string_pretty(&report)?).await?;
+        }
+        Ok(())
+    }
+
+    /// Source and include directories of every declared graph.
+    pub fn watch_paths(&self) -> Vec<PathBuf> {
+        let mut paths = self
+            .binaries
+            .iter()
+            .flat_map(|graph| graph.inner.watch_paths())
+            .collect::<Vec<_>>();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    pub async fn clean(&self, objects_only: bool) -> Result<()> {
+        let mut cache_dirs = self
+            .binaries
+            .iter()
+            .map(|graph| graph.inner.cache_dir())
+            .collect::<Vec<_>>();
+        cache_dirs.push(
+            self.args
+                .target_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(graph::Graph::CACHE_DIR)),
+        );
+        cache_dirs.sort();
+        cache_dirs.dedup();
+        for cache_dir in cache_dirs {
+            graph::Graph::clean_cache(&cache_dir, objects_only).await?;
+        }
+        if !objects_only {
+            for graph in &self.binaries {
+                graph.inner.clean().await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn write_database(&self, path: Option<PathBuf>) -> Result<()> {
+        let path = path.unwrap_or_else(|| PathBuf::from("compile_commands.json"));
+        let mut commands = Vec::new();
+        for graph in &self.binaries {
+            commands.extend(graph.inner.compile_commands().await?);
         }
+        if let crate::Action::GenDatabase { append: true } = self.args.command
+            && let Ok(existing) = tokio::fs::read_to_string(&path).await
+        {
+            let mut existing: Vec<file::CompileCommand> = serde_json::from_str(&existing)
+                .map_err(|e| anyhow::anyhow!("cannot append to {}: {e}", path.display()))?;
+            // entries generated now are newer than whatever was recorded for the same file
+            existing.retain(|old| !commands.iter().any(|new| new.file == old.file));
+            existing.append(&mut commands);
+            commands = existing;
+        }
+        let json = serde_json::to_string_pretty(&commands)?;
+        tokio::fs::write(&path, json).await?;
+        tracing::info!("[Database]: wrote {} entries to {}", commands.len(), path.display());
+        Ok(())
     }
 
     pub async fn generate_database(
         _: Lua,
-        _: LuaUserDataRef<Self>,
-        _: Option<PathBuf>,
+        this: LuaUserDataRef<Self>,
+        path: Option<PathBuf>,
     ) -> LuaResult<bool> {
+        this.write_database(path).await.into_lua_err()?;
         Ok(true)
     }
+
+    /// Runs `binary` with its output prefixed by the binary's path. Returns
+    /// whether it succeeded, or `None` if it could not be started.
+    pub async fn run_binary(&self, binary: PathBuf, args: Vec<String>) -> Option<bool> {
+        let raw_binary = binary.clone();
+        let binary = binary
+            .absolutize()
+            .map(|path| path.to_path_buf())
+            .unwrap_or(binary);
+        let mut cmd = Command::new(&binary);
+        // interactive programs read straight from the terminal; only their output is prefixed
+        cmd.stdin(std::process::Stdio::inherit());
+        cmd.kill_on_drop(true);
+        cmd.stdout(std::process::Stdio::piped());
+        cmd.stderr(std::process::Stdio::piped());
+        cmd.args(&args);
+        cmd.envs(self.args.env.iter().cloned());
+        {
+            let mut cmd = format!("\"{}\"", binary.display());
+            args.iter().for_each(|arg| {
+                cmd.push_str(&format!(", \"{arg}\""));
+            });
+            tracing::info!("Running: {}", cmd);
+        }
+        let process = cmd.spawn();
+        match process {
+            Err(e) => {
+                tracing::error!("failed to run {:?}: {e}", cmd.as_std());
+                self.record_run_failure(1);
+                None
+            }
+            Ok(mut process) => {
+                if let (Some(stdout), Some(stderr)) =
+                    (process.stdout.take(), process.stderr.take())
+                {
+                    tokio::spawn({
+                        let raw_binary = raw_binary.clone();
+                        async move {
+                            let reader = BufReader::new(stdout);
+                            let mut lines = reader.lines();
+                            while let Ok(Some(line)) = lines.next_line().await {
+                                let out = format!("[{}]: {}\n", raw_binary.display(), line);
+                                _ = tokio::io::stdout().write_all(out.as_bytes()).await;
+                            }
+                        }
+                    });
+                    tokio::spawn(async move {
+                        let reader = BufReader::new(stderr);
+                        let mut lines = reader.lines();
+                        while let Ok(Some(line)) = lines.next_line().await {
+                            let out = format!("[{}]: {}\n", raw_binary.display(), line);
+                            _ = tokio::io::stderr().write_all(out.as_bytes()).await;
+                        }
+                    });
+                }
+                if let Ok(status) = process.wait().await {
+                    if !status.success() {
+                        // programs killed by a signal have no exit code
+                        let code = status.code().and_then(|code| u8::try_from(code).ok());
+                        self.record_run_failure(code.filter(|code| *code != 0).unwrap_or(1));
+                    }
+                    Some(status.success())
+                } else {
+                    self.record_run_failure(1);
+                    None
+                }
+            }
+        }
+    }
 }
 
 impl LuaUserData for Build {
     fn add_methods<M: LuaUserDataMethods<Self>>(methods: &mut M) {
-        methods.add_method_mut("add_binary", |lua, this, args: LuaValue| {
-            let mut graph = lua.from_value::<graph::Graph>(args)?;
-            graph.full_rebuild = this.args.full_rebuild;
-            this.binaries.push(Graph {
-                inner: graph.clone(),
-            });
-            let graph = Graph { inner: graph };
-            Ok(graph)
-        });
+        methods.add_method_mut(
+            "add_binary",
+            |lua, this, (args, deps): (LuaValue, Option<Vec<LuaUserDataRef<Graph>>>)| {
+                let graph = lua.from_value::<graph::Graph>(args)?;
+                let deps = deps
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|dep| (*dep).clone())
+                    .collect();
+                Ok(this.add_graph(graph, deps))
+            },
+        );
         methods.add_async_method_mut(
             "install",
-            async |_, _, mut arg: LuaUserDataRefMut<TargetHandle>| {
-                let path = match arg.deref_mut() {
-                    TargetHandle::InProgress(handle) => {
-                        let path = handle.await.into_lua_err()?.ok();
-                        *arg = TargetHandle::Done(path.clone());
-                        path
+            async |_, _, mut arg: LuaUserDataRefMut<TargetHandle>| arg.finish().await,
+        );
+        methods.add_async_method(
+            "join",
+            async |_, _, mut handles: Vec<LuaUserDataRefMut<TargetHandle>>| {
+                // the handles are moved into tasks so they are awaited concurrently
+                let mut set = JoinSet::new();
+                handles.iter_mut().enumerate().for_each(|(index, handle)| {
+                    let mut handle = std::mem::replace(handle.deref_mut(), TargetHandle::Done(None));
+                    set.spawn(async move {
+                        let path = handle.finish().await;
+                        (index, handle, path)
+                    });
+                });
+                let mut paths = vec![None; handles.len()];
+                // every build is awaited before failing, so none is left running unreported
+                let mut error = None;
+                while let Some(result) = set.join_next().await {
+                    let (index, handle, path) = result.into_lua_err()?;
+                    *handles[index] = handle;
+                    match path {
+                        Ok(path) => paths[index] = path,
+                        Err(e) => _ = error.get_or_insert(e),
                     }
-                    TargetHandle::Done(path) => path.clone(),
-                };
-                Ok(path)
+                }
+                match error {
+                    Some(e) => Err(e),
+                    None => Ok(paths),
+                }
             },
         );
         methods.add_method("default_toolchain", |lua, _, _: ()| {
             lua.to_value(&ToolChain::platform_default())
         });
+        methods.add_async_method(
+            "toolchain_version",
+            async |lua, _, tool_chain: Option<LuaValue>| {
+                let tool_chain = match tool_chain {
+                    Some(tool_chain) => lua.from_value::<ToolChain>(tool_chain)?,
+                    None => ToolChain::platform_default(),
+                };
+                Ok(tool_chain.version().await)
+            },
+        );
         methods.add_method("default_opt_level", |lua, this, _: ()| {
             let opt_lvl = if this.args.release {
                 OptimizationLevel::Release
@@ -96,70 +629,374 @@ impl LuaUserData for Build {
             lua.to_value(&Os::current())
         });
         methods.add_method("wants_run", |_, this, _: ()| {
-            Ok(this.args.command == crate::Action::Run)
+            Ok(this.wants_run())
         });
         methods.add_async_method(
             "run",
-            async |_, _, (binary, args): (PathBuf, Option<Vec<String>>)| {
-                let args = args.unwrap_or(Vec::new());
-                let raw_binary = binary.clone();
-                let binary = binary
-                    .absolutize()
-                    .map(|path| path.to_path_buf())
-                    .unwrap_or(binary);
-                let mut cmd = Command::new(&binary);
-                cmd.stdout(std::process::Stdio::piped());
-                cmd.stderr(std::process::Stdio::piped());
-                cmd.args(&args);
-                {
-                    let mut cmd = format!("\"{}\"", binary.display());
-                    args.iter().for_each(|arg| {
-                        cmd.push_str(&format!(", \"{arg}\""));
-                    });
-                    tracing::info!("Running: {}", cmd);
-                }
-                let process = cmd.spawn();
-                Ok(match process {
-                    Err(e) => {
-                        tracing::error!("failed to run {:?}: {e}", cmd.as_std());
-                        None
-                    }
-                    Ok(mut process) => {
-                        if let (Some(stdout), Some(stderr)) =
-                            (process.stdout.take(), process.stderr.take())
-                        {
-                            tokio::spawn({
-                                let raw_binary = raw_binary.clone();
-                                async move {
-                                    let reader = BufReader::new(stdout);
-                                    let mut lines = reader.lines();
-                                    while let Ok(Some(line)) = lines.next_line().await {
-                                        let out = format!("[{}]: {}\n", raw_binary.display(), line);
-                                        _ = tokio::io::stdout().write_all(out.as_bytes()).await;
-                                    }
-                                }
-                            });
-                            tokio::spawn(async move {
-                                let reader = BufReader::new(stderr);
-                                let mut lines = reader.lines();
-                                while let Ok(Some(line)) = lines.next_line().await {
-                                    let out = format!("[{}]: {}\n", raw_binary.display(), line);
-                                    _ = tokio::io::stderr().write_all(out.as_bytes()).await;
-                                }
-                            });
-                        }
-                        if let Ok(status) = process.wait().await {
-                            Some(status.success())
-                        } else {
-                            None
-                        }
-                    }
-                })
+            async |_, this, (binary, args): (PathBuf, Option<Vec<String>>)| {
+                Ok(this.run_binary(binary, args.unwrap_or_default()).await)
             },
         );
         methods.add_method("should_generate_database", |_, this, _: ()| {
-            Ok(this.args.command == crate::Action::GenDatabase)
+            Ok(matches!(this.args.command, crate::Action::GenDatabase { .. }))
         });
         methods.add_async_method("generate_database", Self::generate_database);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Calls the function `script` evaluates to with a build for `args` and `dir`.
+    async fn run_script<R: FromLuaMulti>(args: &[&str], dir: &Path, script: &str) -> LuaResult<R> {
+        let lua = Lua::new();
+        let build = lua.create_userdata(Build::new(crate::Cli::try_parse_from(args).unwrap()))?;
+        let function = lua.load(script).eval_async::<LuaFunction>().await?;
+        function
+            .call_async((build, dir.display().to_string()))
+            .await
+    }
+
+    /// The build after the function `script` evaluates to has declared its binaries.
+    async fn declare(args: &[&str], dir: &Path, script: &str) -> LuaResult<Build> {
+        let lua = Lua::new();
+        let build = lua.create_userdata(Build::new(crate::Cli::try_parse_from(args).unwrap()))?;
+        let function = lua.load(script).eval_async::<LuaFunction>().await?;
+        function
+            .call_async::<()>((&build, dir.display().to_string()))
+            .await?;
+        build.take()
+    }
+
+    #[tokio::test]
+    async fn sources_lists_the_collected_files() {
+        let dir = crate::scratch_dir("sources");
+        std::fs::create_dir_all(dir.join("src/gen")).unwrap();
+        ["src/main.c", "src/util.c", "src/gen/table.c"]
+            .iter()
+            .for_each(|source| std::fs::write(dir.join(source), "").unwrap());
+        let mut sources: Vec<String> = run_script(
+            &["cargoc", "build"],
+            &dir,
+            r#"return function(build, dir)
+                local app = build:add_binary({
+                    tool_chain = "Gcc", opt_level = "Debug", output = dir .. "/app",
+                    files = { dir .. "/src" }, excludes = { dir .. "/src/gen" },
+                })
+                return app:sources()
+            end"#,
+        )
+        .await
+        .unwrap();
+        sources.sort();
+        let expected =
+            ["src/main.c", "src/util.c"].map(|source| dir.join(source).display().to_string());
+        assert_eq!(sources, expected);
+    }
+
+    #[test]
+    fn jobs_are_bounded_by_the_jobs_flag() {
+        let jobs = |args: &[&str]| {
+            Build::new(crate::Cli::try_parse_from(args).unwrap())
+                .jobs
+                .available_permits()
+        };
+        assert_eq!(jobs(&["cargoc", "build", "-j", "3"]), 3);
+        assert_eq!(jobs(&["cargoc", "build", "--jobs", "0"]), 1);
+        assert!(jobs(&["cargoc", "build"]) >= 1);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn the_first_failed_run_sets_the_exit_code() {
+        async fn run(build: &Build, script: &str) -> Option<bool> {
+            let args = vec!["-c".to_string(), script.to_string()];
+            build.run_binary(PathBuf::from("/bin/sh"), args).await
+        }
+
+        let build = Build::new(crate::Cli::try_parse_from(["cargoc", "run"]).unwrap());
+        assert_eq!(run(&build, "exit 0").await, Some(true));
+        assert_eq!(build.run_failure(), None);
+        assert_eq!(run(&build, "exit 3").await, Some(false));
+        assert_eq!(run(&build, "exit 5").await, Some(false));
+        assert_eq!(build.run_failure(), Some(3));
+
+        let build = Build::new(crate::Cli::try_parse_from(["cargoc", "run"]).unwrap());
+        assert_eq!(
+            build
+                .run_binary(PathBuf::from("/nonexistent/app"), Vec::new())
+                .await,
+            None
+        );
+        assert_eq!(build.run_failure(), Some(1));
+    }
+
+    #[test]
+    fn jobs_are_read_from_makeflags() {
+        assert_eq!(Build::makeflags_jobs("-j8"), Some(8));
+        assert_eq!(
+            Build::makeflags_jobs("s -j 4 --no-print-directory"),
+            Some(4)
+        );
+        assert_eq!(Build::makeflags_jobs("--jobs=3"), Some(3));
+        // a bare `-j` means unlimited to make; there is no count to take
+        assert_eq!(Build::makeflags_jobs("-j --jobserver-auth=3,4"), None);
+        assert_eq!(Build::makeflags_jobs(""), None);
+        assert_eq!(Build::resolve_jobs(Some(2)), 2);
+    }
+
+    #[tokio::test]
+    async fn shared_dependencies_build_within_the_job_limit() {
+        let dir = crate::scratch_dir("dependencies");
+        std::fs::write(dir.join("foo.c"), "int foo(void) { return 1; }\n").unwrap();
+        std::fs::write(
+            dir.join("a.c"),
+            "int foo(void);\nint main(void) { return foo() - 1; }\n",
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("b.c"),
+            "int foo(void);\nint main(void) { return foo() - 1; }\n",
+        )
+        .unwrap();
+        // one job: a link waiting on its dependency must not hold the only slot
+        let script = r#"return function(build, dir)
+            local function binary(name, type, deps)
+                return build:add_binary({
+                    tool_chain = "Gcc", opt_level = "Debug", type = type, output = dir .. "/" .. name,
+                    files = { dir .. "/" .. name .. ".c" }, cache_dir = dir .. "/.cargoc",
+                }, deps)
+            end
+            local foo = binary("foo", "StaticLib")
+            local a, b = binary("a", "Executable", { foo }), binary("b", "Executable", { foo })
+            return build:join({ a:build(), b:build() })
+        end"#;
+        let args = ["cargoc", "-q", "-j", "1", "build"];
+        let paths = tokio::time::timeout(
+            Duration::from_secs(60),
+            run_script::<Vec<String>>(&args, &dir, script),
+        )
+        .await
+        .expect("the build deadlocked")
+        .unwrap();
+        assert_eq!(
+            paths,
+            ["a", "b"].map(|name| dir.join(name).display().to_string())
+        );
+        assert!(dir.join("libfoo.a").is_file());
+    }
+
+    #[tokio::test]
+    async fn graph_dump_links_sources_objects_and_dependencies() {
+        let dir = crate::scratch_dir("dump_graph");
+        std::fs::write(dir.join("foo.c"), "").unwrap();
+        std::fs::write(dir.join("main.c"), "").unwrap();
+        let script = r#"return function(build, dir)
+            local function binary(name, type, deps)
+                return build:add_binary({
+                    tool_chain = "Gcc", opt_level = "Debug", type = type, output = dir .. "/" .. name,
+                    files = { dir .. "/" .. name .. ".c" }, cache_dir = dir .. "/.cargoc",
+                }, deps)
+            end
+            binary("main", "Executable", { binary("foo", "StaticLib") })
+        end"#;
+        let build = declare(&["cargoc", "build"], &dir, script).await.unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&build.dump_graph(crate::GraphFormat::Json).await.unwrap())
+                .unwrap();
+        let main = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|binary| binary["output"] == dir.join("main").display().to_string())
+            .unwrap();
+        assert_eq!(
+            main["dependencies"],
+            serde_json::json!([dir.join("libfoo.a")])
+        );
+        assert_eq!(
+            main["objects"][0]["source"],
+            serde_json::json!(dir.join("main.c"))
+        );
+        let object = main["objects"][0]["object"].as_str().unwrap();
+
+        let dot = build.dump_graph(crate::GraphFormat::Dot).await.unwrap();
+        let edge = |from: &dyn std::fmt::Display, to: &dyn std::fmt::Display| {
+            format!("    \"{from}\" -> \"{to}\";\n")
+        };
+        let main_path = dir.join("main");
+        assert!(dot.starts_with("digraph cargoc {\n") && dot.ends_with('}'));
+        assert!(dot.contains(&edge(&dir.join("main.c").display(), &object)));
+        assert!(dot.contains(&edge(&object, &main_path.display())));
+        assert!(dot.contains(&edge(&dir.join("libfoo.a").display(), &main_path.display())));
+    }
+
+    #[tokio::test]
+    async fn appending_to_the_database_replaces_entries_per_file() {
+        let dir = crate::scratch_dir("database");
+        std::fs::write(dir.join("main.c"), "").unwrap();
+        let database = dir.join("compile_commands.json");
+        let existing = serde_json::json!([
+            { "directory": "/other", "command": "cc -c lib.c", "file": "/other/lib.c" },
+            { "directory": "/old", "arguments": ["cc", "-c", "main.c"], "file": dir.join("main.c") },
+        ]);
+        std::fs::write(&database, existing.to_string()).unwrap();
+        let script = r#"return function(build, dir)
+            build:add_binary({ tool_chain = "Gcc", opt_level = "Debug", output = dir .. "/app", files = { dir .. "/main.c" } })
+        end"#;
+        let files = || -> Vec<(String, String)> {
+            let database: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(&database).unwrap()).unwrap();
+            database
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|entry| (entry["file"].to_string(), entry["directory"].to_string()))
+                .collect()
+        };
+
+        let args = ["cargoc", "gen-database", "--append"];
+        let build = declare(&args, &dir, script).await.unwrap();
+        build.write_database(Some(database.clone())).await.unwrap();
+        let entries = files();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].0, "\"/other/lib.c\"");
+        assert_ne!(entries[1].1, "\"/old\"");
+        // entries of other tools are written back as they were, without `arguments`
+        let written: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&database).unwrap()).unwrap();
+        assert_eq!(written[0], existing[0]);
+
+        let build = declare(&["cargoc", "gen-database"], &dir, script)
+            .await
+            .unwrap();
+        build.write_database(Some(database.clone())).await.unwrap();
+        assert_eq!(files().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn targets_are_listed_with_their_type_and_toolchain() {
+        let dir = crate::scratch_dir("list_targets");
+        let script = r#"return function(build, dir)
+            build:add_binary({ tool_chain = "Gcc", opt_level = "Debug", type = "StaticLib", output = dir .. "/foo", files = {} })
+            build:add_binary({
+                tool_chain = { compiler = "tcc", linker = "tcc" }, opt_level = "Debug", output = dir .. "/app", files = {},
+            })
+        end"#;
+        let build = declare(&["cargoc", "build"], &dir, script).await.unwrap();
+        let text = build.list_targets(crate::ListFormat::Text).unwrap();
+        let expected = format!(
+            "{}\tStaticLib\tGcc\n{}\tExecutable\ttcc",
+            dir.join("libfoo.a").display(),
+            dir.join("app").display()
+        );
+        assert_eq!(text, expected);
+
+        let json: serde_json::Value =
+            serde_json::from_str(&build.list_targets(crate::ListFormat::Json).unwrap()).unwrap();
+        assert_eq!(json[0]["type"], "StaticLib");
+        assert_eq!(json[0]["tool_chain"], "Gcc");
+        assert_eq!(json[1]["tool_chain"]["compiler"], "tcc");
+    }
+
+    #[tokio::test]
+    async fn only_named_targets_are_built() {
+        let dir = crate::scratch_dir("targets");
+        let script = r#"return function(build, dir)
+            local function binary(name, type, deps)
+                return build:add_binary({
+                    tool_chain = "Gcc", opt_level = "Debug", type = type, output = dir .. "/" .. name, files = {},
+                }, deps)
+            end
+            binary("app", "Executable", { binary("foo", "StaticLib") })
+            binary("tool", "Executable")
+        end"#;
+        let skipped = |build: &Build| {
+            build
+                .binaries
+                .iter()
+                .map(|graph| (graph.inner.name(), graph.skip))
+                .collect::<Vec<_>>()
+        };
+        let build = declare(&["cargoc", "build", "app"], &dir, script)
+            .await
+            .unwrap();
+        // `foo` is still built, as a dependency of `app`
+        let expected = [("foo", true), ("app", false), ("tool", true)];
+        assert_eq!(
+            skipped(&build),
+            expected.map(|(name, skip)| (name.to_string(), skip))
+        );
+        let targets = ["app", "foo", "nope"].map(String::from);
+        assert_eq!(build.unknown_targets(&targets), ["nope"]);
+
+        let build = declare(&["cargoc", "build"], &dir, script).await.unwrap();
+        assert!(skipped(&build).iter().all(|(_, skip)| !skip));
+
+        let lua = Lua::new();
+        let args = crate::Cli::try_parse_from(["cargoc", "build"]).unwrap();
+        let declared = lua.create_userdata(Build::declare_only(args)).unwrap();
+        let function = lua.load(script).eval_async::<LuaFunction>().await.unwrap();
+        function
+            .call_async::<()>((&declared, dir.display().to_string()))
+            .await
+            .unwrap();
+        assert!(
+            skipped(&declared.borrow::<Build>().unwrap())
+                .iter()
+                .all(|(_, skip)| *skip)
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn run_shares_stdin_with_cargoc() {
+        let Ok(stdin) = std::fs::read_link("/proc/self/fd/0") else {
+            return;
+        };
+        let script = format!(
+            "test \"$(readlink /proc/self/fd/0)\" = '{}'",
+            stdin.display()
+        );
+        let build = Build::new(crate::Cli::try_parse_from(["cargoc", "run"]).unwrap());
+        let args = vec!["-c".to_string(), script];
+        assert_eq!(
+            build.run_binary(PathBuf::from("/bin/sh"), args).await,
+            Some(true)
+        );
+    }
+
+    #[tokio::test]
+    async fn join_fails_once_every_build_finished() {
+        let dir = crate::scratch_dir("join");
+        std::fs::write(dir.join("good.c"), "int main(void) { return 0; }\n").unwrap();
+        std::fs::write(dir.join("bad.c"), "int main(void) { return missing; }\n").unwrap();
+        let script = r#"return function(build, dir)
+            local function binary(name)
+                return build:add_binary({
+                    tool_chain = "Gcc", opt_level = "Debug", output = dir .. "/" .. name,
+                    files = { dir .. "/" .. name .. ".c" }, cache_dir = dir .. "/.cargoc",
+                })
+            end
+            local bad, good = binary("bad"):build(), binary("good"):build()
+            local ok, err = pcall(build.join, build, { bad, good })
+            local reinstalled, again = pcall(build.install, build, bad)
+            return ok, tostring(err), reinstalled, tostring(again), build:install(good)
+        end"#;
+        let (ok, error, reinstalled, again, installed): (bool, String, bool, String, String) =
+            run_script(&["cargoc", "-q", "build"], &dir, script)
+                .await
+                .unwrap();
+        assert!(!ok);
+        assert!(error.contains("failed to compile"), "{error}");
+        // the failed handle keeps failing instead of looking skipped
+        assert!(!reinstalled);
+        assert!(again.contains("failed to compile"), "{again}");
+        // the good build still finished, and installing its handle again is instant
+        assert_eq!(installed, dir.join("good").display().to_string());
+        assert!(dir.join("good").is_file());
+    }
+}
@@ -1,17 +1,96 @@
 mod build;
+mod manifest;
+mod watch;
 use anyhow::Result;
 use build::Build;
-use clap::{Parser, Subcommand};
+use manifest::Manifest;
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use mlua::prelude::*;
-use std::{path::PathBuf, process::ExitCode};
+use std::{
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Instant,
+};
 use tracing::Level;
-use tracing_subscriber::prelude::*;
+use tracing_subscriber::{filter::LevelFilter, fmt::writer::BoxMakeWriter, prelude::*};
 
 #[derive(Debug, Clone, Subcommand, PartialEq, Eq)]
 enum Action {
-    Build,
+    Build {
+        /// Only build the binaries with these names, plus their dependencies
+        targets: Vec<String>,
+    },
     Run,
-    GenDatabase,
+    GenDatabase {
+        #[arg(
+            long,
+            help = "Merge into an existing compile_commands.json, replacing entries for the same file"
+        )]
+        append: bool,
+    },
+    /// Remove the build cache and the declared outputs
+    Clean {
+        #[arg(long, help = "Only remove object files, keep final binaries")]
+        objects_only: bool,
+    },
+    /// Print a source file after preprocessing, using its binary's flags
+    Preprocess {
+        file: PathBuf,
+        #[arg(short, long, help = "Write to a file instead of stdout")]
+        output: Option<PathBuf>,
+    },
+    /// Compile a source file to an assembly listing next to its object
+    Asm { file: PathBuf },
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum Color {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    /// `Auto` colors only when stderr, where diagnostics go, is a terminal.
+    fn enabled(self) -> bool {
+        match self {
+            Self::Auto => std::io::stderr().is_terminal(),
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum MessageFormat {
+    /// Log lines for people
+    #[default]
+    Human,
+    /// One JSON object per build event on stdout, logs move to stderr
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+enum DepfileFormat {
+    /// Only the make rules (`<object>.d`) the compiler writes
+    #[default]
+    Make,
+    /// The make rules plus the same dependencies as JSON (`<object>.d.json`)
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum GraphFormat {
+    Dot,
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ListFormat {
+    Text,
+    Json,
 }
 
 #[derive(Debug, Clone, Parser)]
@@ -22,7 +101,7 @@ struct Cli {
         short,
         long,
         default_value = "build.lua",
-        help = "Build script path"
+        help = "Build script path, or a `.toml`/`.json` manifest"
     )]
     build_scirpt: PathBuf,
     #[command(subcommand)]
@@ -31,20 +110,241 @@ struct Cli {
     full_rebuild: bool,
     #[arg(short, long, global = true)]
     release: bool,
-    #[arg(long, global = true, help = "Print verbose logs")]
-    verbose: bool,
+    #[arg(
+        short,
+        long,
+        global = true,
+        action = ArgAction::Count,
+        help = "Print verbose logs (-v for commands, -vv for everything)"
+    )]
+    verbose: u8,
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "Only print a summary instead of every compiled file"
+    )]
+    quiet: bool,
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "Number of parallel compile jobs [default: CARGOC_JOBS, then -jN from MAKEFLAGS, then available parallelism]"
+    )]
+    jobs: Option<usize>,
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "Compile every file before reporting failures instead of stopping at the first"
+    )]
+    keep_going: bool,
+    #[arg(long, global = true, help = "Rebuild whenever a source or header changes")]
+    watch: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Detect changes by modification time instead of content hashes"
+    )]
+    mtime_only: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Log why every object and binary is, or is not, rebuilt"
+    )]
+    explain: bool,
+    #[arg(
+        long,
+        global = true,
+        help = "Print every command a full rebuild would run without running them"
+    )]
+    dry_run: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "N",
+        help = "Retry links failing on locked files N times [default: 2 on Windows, 0 elsewhere]"
+    )]
+    link_retries: Option<u32>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "SECS",
+        help = "Kill any compile or link step still running after SECS seconds"
+    )]
+    timeout: Option<u64>,
+    #[arg(
+        long,
+        global = true,
+        help = "Resolve relative paths in the build script against the working directory instead of the script's"
+    )]
+    relative_to_cwd: bool,
+    #[arg(
+        long = "env",
+        global = true,
+        value_name = "KEY=VALUE",
+        value_parser = parse_env,
+        help = "Environment variable for compilers, linkers and run targets"
+    )]
+    env: Vec<(String, String)>,
+    #[arg(
+        long,
+        global = true,
+        help = "Directory for build artifacts [default: .cargoc]"
+    )]
+    target_dir: Option<PathBuf>,
+    #[arg(long, global = true, help = "Install prefix for built binaries")]
+    prefix: Option<PathBuf>,
+    #[arg(long, global = true, help = "Print how long each compile and link step took")]
+    timings: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "PATH",
+        help = "Write step timings as JSON to PATH"
+    )]
+    timings_json: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = Color::Auto,
+        help = "Color compiler diagnostics and log output"
+    )]
+    color: Color,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = DepfileFormat::Make,
+        help = "Format of the dependency files kept next to each object in `<cache>/<profile>/obj/<output>/`"
+    )]
+    depfile_format: DepfileFormat,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t = MessageFormat::Human,
+        help = "How build events are reported"
+    )]
+    message_format: MessageFormat,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "dot",
+        help = "Print how sources, objects and binaries connect instead of building"
+    )]
+    graph: Option<GraphFormat>,
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "text",
+        help = "List the declared binaries instead of building"
+    )]
+    list_targets: Option<ListFormat>,
+}
+
+fn level_filter(verbose: u8) -> LevelFilter {
+    match verbose {
+        0 => LevelFilter::INFO,
+        1 => LevelFilter::DEBUG,
+        _ => LevelFilter::TRACE,
+    }
+}
+
+fn parse_env(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got `{arg}`"))
+}
+
+/// The `fs` table of filesystem helpers available to build scripts.
+/// Relative paths are resolved against `base`, the build script's directory.
+fn fs_module(lua: &Lua, base: &Path) -> LuaResult<LuaTable> {
+    let fs = lua.create_table()?;
+    let base_dir = base.to_path_buf();
+    fs.set(
+        "exists",
+        lua.create_async_function(move |_, path: PathBuf| {
+            let path = base_dir.join(path);
+            async move { tokio::fs::try_exists(path).await.into_lua_err() }
+        })?,
+    )?;
+    let base_dir = base.to_path_buf();
+    fs.set(
+        "read_to_string",
+        lua.create_async_function(move |_, path: PathBuf| {
+            let path = base_dir.join(path);
+            async move { tokio::fs::read_to_string(path).await.into_lua_err() }
+        })?,
+    )?;
+    let base_dir = base.to_path_buf();
+    fs.set(
+        "mkdir",
+        lua.create_async_function(move |_, path: PathBuf| {
+            let path = base_dir.join(path);
+            async move { tokio::fs::create_dir_all(path).await.into_lua_err() }
+        })?,
+    )?;
+    let base_dir = base.to_path_buf();
+    fs.set(
+        "list_dir",
+        lua.create_async_function(move |_, path: PathBuf| {
+            let base = base_dir.clone();
+            let path = base_dir.join(path);
+            async move {
+                let mut entries = Vec::new();
+                let mut dir = tokio::fs::read_dir(path).await.into_lua_err()?;
+                while let Some(entry) = dir.next_entry().await.into_lua_err()? {
+                    let path = entry.path();
+                    let path = path.strip_prefix(&base).unwrap_or(&path);
+                    entries.push(path.to_string_lossy().into_owned());
+                }
+                entries.sort();
+                Ok(entries)
+            }
+        })?,
+    )?;
+    Ok(fs)
+}
+
+/// Runs the build script, or manifest, against `build`. The outer error means the
+/// script could not be loaded, the inner one that running it failed.
+async fn run_script(lua: &Lua, args: &Cli, build: &LuaAnyUserData) -> Result<LuaResult<()>> {
+    if Manifest::is_manifest(&args.build_scirpt) {
+        let manifest = Manifest::load(&args.build_scirpt).await?;
+        let res = manifest.run(&mut *build.borrow_mut::<Build>()?).await;
+        Ok(res.into_lua_err())
+    } else {
+        let chunk = lua.load(args.build_scirpt.clone());
+        let out = chunk.eval_async::<LuaFunction>().await?;
+        Ok(out.call_async::<()>(build).await)
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<ExitCode> {
+    let args = Cli::parse();
     tracing_subscriber::registry()
         .with(
             tracing_subscriber::fmt::layer()
                 .with_file(false)
                 .with_target(false)
+                .with_ansi(args.color.enabled())
+                .with_writer(match args.message_format {
+                    MessageFormat::Human => BoxMakeWriter::new(std::io::stdout),
+                    // stdout only carries the JSON events
+                    MessageFormat::Json => BoxMakeWriter::new(std::io::stderr),
+                })
                 .without_time(),
         )
-        .with(tracing_subscriber::filter::LevelFilter::TRACE)
+        .with(level_filter(args.verbose))
         .with(tracing_subscriber::filter::filter_fn(|meta| {
             if let Some(path) = meta.module_path() {
                 path != "mio::poll"
@@ -54,7 +354,6 @@ async fn main() -> Result<ExitCode> {
         }))
         .init();
 
-    let args = Cli::parse();
     let lua = Lua::new();
 
     lua.globals().set(
@@ -76,19 +375,191 @@ async fn main() -> Result<ExitCode> {
         })?,
     )?;
 
-    let chunk = lua.load(args.build_scirpt.clone());
-    let out = chunk.eval_async::<LuaFunction>().await?;
-    let build = Build::new(args.clone());
-    let build = lua.create_userdata(build)?;
-    let res = out.call_async::<()>(&build).await;
-    let exit = match res {
-        Ok(_) => ExitCode::SUCCESS,
-        Err(e) => {
-            if args.verbose {
+    // script paths mean the same wherever cargoc is started from, like cargo's manifests
+    let script_dir = if args.relative_to_cwd {
+        PathBuf::new()
+    } else {
+        args.build_scirpt.parent().map(Path::to_path_buf).unwrap_or_default()
+    };
+    let base = script_dir.clone();
+    lua.globals().set(
+        "glob",
+        lua.create_function(move |_, pattern: String| {
+            let pattern = base.join(pattern);
+            let paths = cbuild::graph::expand_glob(&pattern.to_string_lossy()).into_lua_err()?;
+            Ok(paths
+                .iter()
+                .map(|path| path.strip_prefix(&base).unwrap_or(path))
+                .map(|path| path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>())
+        })?,
+    )?;
+
+    lua.globals().set("fs", fs_module(&lua, &script_dir)?)?;
+
+    loop {
+        let start = Instant::now();
+        if let Action::Build { targets } = &args.command
+            && !targets.is_empty()
+        {
+            // scripts build while they declare, so the names are checked against a pass
+            // that only declares; a failing script is left for the real run to report
+            let declared = lua.create_userdata(Build::declare_only(args.clone()))?;
+            if let Ok(Ok(())) = run_script(&lua, &args, &declared).await {
+                let unknown = declared.borrow::<Build>()?.unknown_targets(targets);
+                if !unknown.is_empty() {
+                    tracing::error!("no binary named `{}`", unknown.join("`, `"));
+                    return Ok(ExitCode::FAILURE);
+                }
+            }
+        }
+        let build = lua.create_userdata(Build::new(args.clone()))?;
+        let run = run_script(&lua, &args, &build);
+        let mut res = tokio::select! {
+            res = run => res?,
+            _ = tokio::signal::ctrl_c() => {
+                // every task is dropped once main returns, and with it every
+                // compiler, linker and program, as they are all `kill_on_drop`
+                tracing::error!("interrupted, stopping the build");
+                return Ok(ExitCode::from(130));
+            }
+        };
+        if let Some(format) = args.list_targets {
+            match build.borrow::<Build>()?.list_targets(format) {
+                Ok(targets) => println!("{targets}"),
+                Err(e) => res = res.and(Err(e).into_lua_err()),
+            }
+        }
+        if let Some(format) = args.graph {
+            match build.borrow::<Build>()?.dump_graph(format).await {
+                Ok(graph) => println!("{graph}"),
+                Err(e) => res = res.and(Err(e).into_lua_err()),
+            }
+        }
+        match &args.command {
+            Action::Clean { objects_only } => {
+                let clean = build.borrow::<Build>()?.clean(*objects_only).await;
+                res = res.and(clean.into_lua_err());
+            }
+            Action::Preprocess { file, output } => {
+                let build = build.borrow::<Build>()?;
+                let preprocess = build.preprocess(file, output.as_deref()).await;
+                res = res.and(preprocess.into_lua_err());
+            }
+            Action::Asm { file } => {
+                let assembly = build.borrow::<Build>()?.emit_assembly(file).await;
+                res = res.and(assembly.map(|_| ()).into_lua_err());
+            }
+            _ => {}
+        }
+        build.borrow::<Build>()?.report_timings(start.elapsed()).await?;
+        let run_failure = build.borrow::<Build>()?.run_failure();
+        let exit = match res {
+            Ok(_) => run_failure.map_or(ExitCode::SUCCESS, ExitCode::from),
+            Err(e) => {
                 tracing::error!("{e}");
+                ExitCode::FAILURE
             }
-            ExitCode::FAILURE
+        };
+        if !args.watch {
+            return Ok(exit);
         }
-    };
-    Ok(exit)
+
+        let paths = build.borrow::<Build>()?.watch_paths();
+        // listening for Ctrl-C above replaced the default handler, so it has to be awaited here too
+        let changed = tokio::select! {
+            changed = watch::wait_for_changes(&paths) => changed?,
+            _ = tokio::signal::ctrl_c() => return Ok(ExitCode::from(130)),
+        };
+        changed.iter().for_each(|path| {
+            tracing::info!("rebuilding due to changes in {}", path.display());
+        });
+    }
+}
+
+/// A fresh, empty directory below the system's temporary directory for one test.
+#[cfg(test)]
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cargoc-test-{}-{name}", std::process::id()));
+    _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn verbose_flag_raises_the_log_level() {
+        let verbose = |args: &[&str]| Cli::try_parse_from(args).unwrap().verbose;
+        assert_eq!(
+            level_filter(verbose(&["cargoc", "build"])),
+            LevelFilter::INFO
+        );
+        assert_eq!(
+            level_filter(verbose(&["cargoc", "build", "-v"])),
+            LevelFilter::DEBUG
+        );
+        assert_eq!(
+            level_filter(verbose(&["cargoc", "-vv", "build"])),
+            LevelFilter::TRACE
+        );
+        assert_eq!(
+            level_filter(verbose(&["cargoc", "build", "-vvv"])),
+            LevelFilter::TRACE
+        );
+    }
+
+    #[test]
+    fn color_can_be_forced_either_way() {
+        let color = |args: &[&str]| Cli::try_parse_from(args).unwrap().color;
+        assert_eq!(color(&["cargoc", "build"]), Color::Auto);
+        assert!(color(&["cargoc", "build", "--color", "always"]).enabled());
+        assert!(!color(&["cargoc", "--color", "never", "build"]).enabled());
+    }
+
+    #[tokio::test]
+    async fn fs_module_resolves_paths_against_the_script_dir() {
+        let dir = scratch_dir("fs_module");
+        std::fs::write(dir.join("version.txt"), "1.2.3\n").unwrap();
+        let lua = Lua::new();
+        let fs = fs_module(&lua, &dir).unwrap();
+        let call = |name: &str| fs.get::<LuaFunction>(name).unwrap();
+
+        assert!(
+            call("exists")
+                .call_async::<bool>("version.txt")
+                .await
+                .unwrap()
+        );
+        assert!(!call("exists").call_async::<bool>("missing").await.unwrap());
+        let version = call("read_to_string")
+            .call_async::<String>("version.txt")
+            .await
+            .unwrap();
+        assert_eq!(version, "1.2.3\n");
+        assert!(
+            call("read_to_string")
+                .call_async::<String>("missing")
+                .await
+                .is_err()
+        );
+
+        call("mkdir").call_async::<()>("gen/include").await.unwrap();
+        assert!(dir.join("gen/include").is_dir());
+        let entries = call("list_dir")
+            .call_async::<Vec<String>>(".")
+            .await
+            .unwrap();
+        assert_eq!(entries, ["gen", "version.txt"]);
+        let absolute = dir.join("gen").to_string_lossy().into_owned();
+        assert_eq!(
+            call("list_dir")
+                .call_async::<Vec<String>>(absolute)
+                .await
+                .unwrap(),
+            ["gen/include"]
+        );
+    }
 }
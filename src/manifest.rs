@@ -0,0 +1,103 @@
+use crate::{Action, build::Build};
+use anyhow::Result;
+use cbuild::graph;
+use serde::Deserialize;
+use std::path::Path;
+
+/// Declarative alternative to `build.lua`: a `[[binary]]` list of graphs
+/// driven through the same build, run and clean steps as a script.
+#[derive(Debug, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    binary: Vec<graph::Graph>,
+}
+
+impl Manifest {
+    /// Whether `path` names a TOML or JSON manifest instead of a Lua script.
+    pub fn is_manifest(path: &Path) -> bool {
+        matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("toml" | "json")
+        )
+    }
+
+    pub async fn load(path: &Path) -> Result<Self> {
+        let contents = tokio::fs::read_to_string(path).await?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Ok(serde_json::from_str(&contents)?),
+            _ => Ok(toml::from_str(&contents)?),
+        }
+    }
+
+    /// Declares every binary and builds them in order, running the
+    /// executables for `run`. Cleaning and preprocessing are left to the
+    /// caller as for scripts.
+    pub async fn run(self, build: &mut Build) -> Result<()> {
+        let graphs = self
+            .binary
+            .into_iter()
+            .map(|graph| build.add_graph(graph, Vec::new()))
+            .collect::<Vec<_>>();
+        match build.command() {
+            Action::Clean { .. } | Action::Preprocess { .. } | Action::Asm { .. } => Ok(()),
+            Action::GenDatabase { .. } => build.write_database(None).await,
+            Action::Build { .. } | Action::Run => {
+                for graph in &graphs {
+                    let Some(path) = graph.build_and_install().await? else {
+                        continue;
+                    };
+                    if build.wants_run() && graph.is_executable() {
+                        build.run_binary(path, Vec::new()).await;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[tokio::test]
+    async fn manifests_build_their_binaries_relative_to_themselves() {
+        let dir = std::env::temp_dir().join(format!("cargoc-test-{}-manifest", std::process::id()));
+        _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("main.c"), "int main(void) { return 0; }\n").unwrap();
+        let manifest = dir.join("cargoc.toml");
+        std::fs::write(
+            &manifest,
+            "[[binary]]\ntool_chain = \"Gcc\"\nopt_level = \"Debug\"\nfiles = [\"main.c\"]\noutput = \"app\"\ncache_dir = \".cargoc\"\n",
+        )
+        .unwrap();
+        assert!(Manifest::is_manifest(&manifest) && !Manifest::is_manifest(Path::new("build.lua")));
+
+        let args = crate::Cli::try_parse_from([
+            "cargoc",
+            "-q",
+            "-i",
+            &manifest.display().to_string(),
+            "build",
+        ])
+        .unwrap();
+        let mut build = Build::new(args);
+        Manifest::load(&manifest)
+            .await
+            .unwrap()
+            .run(&mut build)
+            .await
+            .unwrap();
+        assert!(dir.join("app").is_file());
+
+        let json = dir.join("cargoc.json");
+        std::fs::write(
+            &json,
+            r#"{ "binary": [{ "tool_chain": "Gcc", "opt_level": "Debug", "files": [] }] }"#,
+        )
+        .unwrap();
+        assert_eq!(Manifest::load(&json).await.unwrap().binary.len(), 1);
+    }
+}
@@ -0,0 +1,60 @@
+use anyhow::Result;
+use notify::{RecursiveMode, Watcher};
+use std::{path::PathBuf, time::Duration};
+use tokio::sync::mpsc;
+
+/// How long the tree has to stay quiet before a rebuild is started.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Waits until something below `paths` changes and returns every path touched
+/// within the debounce window.
+pub async fn wait_for_changes(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && !event.kind.is_access()
+        {
+            _ = tx.send(event);
+        }
+    })?;
+    for path in paths {
+        if let Err(e) = watcher.watch(path, RecursiveMode::Recursive) {
+            tracing::warn!("not watching `{}`: {e}", path.display());
+        }
+    }
+
+    let mut changed = Vec::new();
+    if let Some(event) = rx.recv().await {
+        changed.extend(event.paths);
+    }
+    while let Ok(Some(event)) = tokio::time::timeout(DEBOUNCE, rx.recv()).await {
+        changed.extend(event.paths);
+    }
+    changed.sort();
+    changed.dedup();
+    Ok(changed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_files_changed_below_a_watched_directory() {
+        let dir = std::env::temp_dir().join(format!("cargoc-test-{}-watch", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("main.c");
+        let edit = async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            tokio::fs::write(&file, "int main(void) { return 0; }")
+                .await
+                .unwrap();
+        };
+        let watch = tokio::time::timeout(
+            Duration::from_secs(10),
+            wait_for_changes(std::slice::from_ref(&dir)),
+        );
+        let (changed, ()) = tokio::join!(watch, edit);
+        assert!(changed.unwrap().unwrap().contains(&file));
+    }
+}